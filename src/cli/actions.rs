@@ -1,21 +1,75 @@
 use crate::config::Config;
-use crate::util::wrap_text;
+use crate::util::{
+    bash_array, format_duration, format_size, format_timestamp, parse_size, wrap_text,
+};
 use crate::Error;
 use coordinator::combine_for_display;
-use clap::Args;
+use itertools::Itertools;
+use clap::{Args, Subcommand, ValueEnum};
 use colored::Colorize;
 use coordinator::endpoints::Endpoints;
 use coordinator::{
-    AddPackages, AddPackagesResponse, RemovePackages, RemovePackagesResponse, Status,
+    AddPackages, AddPackagesResponse, DependencyClosure, ErrorResponse, OverridesUpdate,
+    PackageDetails, PromotePackage, RebuildPackages, RebuildPackagesResponse, RemovePackages,
+    RemovePackagesResponse, ReverseDependencies, Status, StatusCounts,
 };
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use time::OffsetDateTime;
 use tracing::{error, info, warn};
 use ureq::Agent;
 
+/// How many packages are sent to the coordinator in a single request by default.
+const DEFAULT_BATCH_SIZE: usize = 100;
+
 #[derive(Clone, Args)]
 pub struct Add {
     /// The packages to add
     packages: Vec<String>,
+    /// The channel to add the packages to, instead of the coordinator's default one
+    #[arg(long)]
+    channel: Option<String>,
+    /// How many packages are sent to the coordinator per request
+    #[arg(long, default_value_t = DEFAULT_BATCH_SIZE, value_parser = parse_batch_size)]
+    batch_size: usize,
+    /// Build the packages right away without tracking their dependencies, which the builder
+    /// image has to provide
+    #[arg(long)]
+    no_deps: bool,
+    /// Only track the packages, leaving their first build to the coordinator's next update check
+    #[arg(long)]
+    no_build: bool,
+}
+
+fn parse_batch_size(size: &str) -> Result<usize, String> {
+    match size.parse() {
+        Ok(0) | Err(_) => Err(format!("'{size}' is not a positive number")),
+        Ok(size) => Ok(size),
+    }
+}
+
+/// Describes why a batch failed, using the message of the coordinator if it sent one.
+fn describe_request_error(err: ureq::Error) -> String {
+    match err {
+        ureq::Error::Status(code, response) => match response.into_json::<ErrorResponse>() {
+            Ok(response) => response.error,
+            Err(_) => format!("the coordinator responded with status {code}"),
+        },
+        err => err.to_string(),
+    }
+}
+
+/// Reports the batches that failed, so it is clear which packages were left unchanged.
+fn report_failed_batches(action: &str, failed: &[(Vec<String>, String)]) {
+    for (packages, err) in failed {
+        error!(
+            "Failed to {action} {}: {err}",
+            combine_for_display(packages)
+        );
+    }
 }
 
 pub fn add(config: &Config, add: Add) -> Result<u8, Error> {
@@ -27,14 +81,57 @@ pub fn add(config: &Config, add: Add) -> Result<u8, Error> {
         return Ok(1);
     }
 
-    let add_packages = AddPackages {
-        packages: add.packages.into_iter().collect(),
+    if add.no_deps {
+        warn!("Not resolving dependencies. Builds fail if the builder image lacks any of them");
+    }
+
+    let packages: Vec<String> = add.packages.into_iter().sorted().dedup().collect();
+    let mut response = AddPackagesResponse {
+        added: BTreeSet::new(),
+        already_tracked: BTreeSet::new(),
+        not_found: BTreeSet::new(),
+        dependencies: BTreeSet::new(),
+        suggestions: BTreeMap::new(),
+        rejected_limit: BTreeSet::new(),
     };
-    let response: AddPackagesResponse = client
-        .post(&endpoints.add_packages())
-        .send_json(add_packages)
-        .map_err(Box::new)?
-        .into_json()?;
+    let mut failed = Vec::new();
+    for batch in packages.chunks(add.batch_size) {
+        let add_packages = AddPackages {
+            packages: batch.iter().cloned().collect(),
+            channel: add.channel.clone(),
+            resolve_deps: !add.no_deps,
+            build_immediately: !add.no_build,
+        };
+        let result = client
+            .post(&endpoints.add_packages())
+            .send_json(add_packages)
+            .map_err(describe_request_error)
+            .and_then(|response| {
+                response
+                    .into_json::<AddPackagesResponse>()
+                    .map_err(|err| err.to_string())
+            });
+        match result {
+            Ok(batch_response) => {
+                response.added.extend(batch_response.added);
+                response
+                    .already_tracked
+                    .extend(batch_response.already_tracked);
+                response.not_found.extend(batch_response.not_found);
+                response.dependencies.extend(batch_response.dependencies);
+                response.suggestions.extend(batch_response.suggestions);
+                response
+                    .rejected_limit
+                    .extend(batch_response.rejected_limit);
+            }
+            Err(err) => failed.push((batch.to_vec(), err)),
+        }
+    }
+    // The dependencies of one batch may be packages added by another.
+    response
+        .dependencies
+        .retain(|package| !response.added.contains(package));
+    report_failed_batches("add", &failed);
 
     if !response.already_tracked.is_empty() {
         let is_are = if response.already_tracked.len() > 1 {
@@ -52,8 +149,20 @@ pub fn add(config: &Config, add: Add) -> Result<u8, Error> {
             "Could not find {}",
             combine_for_display(&response.not_found)
         );
+        for (package, suggestions) in response.suggestions.iter().sorted() {
+            info!(
+                "Instead of {package}, did you mean {}?",
+                suggestions.join(", ")
+            );
+        }
+    }
+    if !response.rejected_limit.is_empty() {
+        error!(
+            "Did not add {}, as the coordinator would track more packages than it allows",
+            combine_for_display(&response.rejected_limit)
+        );
     }
-    if response.added.is_empty() || !response.not_found.is_empty() {
+    if response.added.is_empty() {
         error!("No changes have been made");
         return Ok(1);
     }
@@ -61,6 +170,84 @@ pub fn add(config: &Config, add: Add) -> Result<u8, Error> {
         "Added {} successfully",
         combine_for_display(&response.added)
     );
+    if !response.dependencies.is_empty() {
+        let dependencies = if response.dependencies.len() > 1 {
+            "dependencies"
+        } else {
+            "dependency"
+        };
+        info!(
+            "Also queued {} {dependencies}: {}",
+            response.dependencies.len(),
+            combine_for_display(&response.dependencies)
+        );
+    }
+    Ok(u8::from(
+        !response.not_found.is_empty() || !response.rejected_limit.is_empty() || !failed.is_empty(),
+    ))
+}
+
+#[derive(Clone, Args)]
+pub struct AddLocal {
+    /// The directory containing the PKGBUILD and its local sources
+    directory: PathBuf,
+    /// The name of the package. The name of the directory if omitted
+    #[arg(long)]
+    name: Option<String>,
+    /// The channel to add the package to, if it isn't tracked yet
+    #[arg(long)]
+    channel: Option<String>,
+}
+
+pub fn add_local(config: &Config, add: AddLocal) -> Result<u8, Error> {
+    let client = Agent::new();
+    let endpoints: Endpoints = config.server.to_endpoints();
+
+    let directory = add.directory.canonicalize()?;
+    let Some(name) = add.name.or_else(|| {
+        directory
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+    }) else {
+        error!("Could not tell the name of the package. Pass it with --name");
+        return Ok(1);
+    };
+    if !directory.join("PKGBUILD").is_file() {
+        error!("{} does not contain a PKGBUILD", directory.display());
+        return Ok(1);
+    }
+
+    // Leaves out what makepkg leaves behind, which the worker would build again anyway.
+    let output = std::process::Command::new("tar")
+        .arg("-C")
+        .arg(&directory)
+        .args([
+            "--exclude=./src",
+            "--exclude=./pkg",
+            "--exclude=*.pkg.tar*",
+            "-cf",
+            "-",
+            ".",
+        ])
+        .output()?;
+    if !output.status.success() {
+        error!(
+            "Failed to pack {}: {}",
+            directory.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return Ok(1);
+    }
+
+    let mut request = client
+        .post(&endpoints.local_package(&name))
+        .set("Content-Type", "application/x-tar");
+    if let Some(channel) = &add.channel {
+        request = request.query("channel", channel);
+    }
+    request.send_bytes(&output.stdout).map_err(Box::new)?;
+
+    info!("Uploaded the sources of {name}, which get built shortly");
     Ok(0)
 }
 
@@ -68,6 +255,9 @@ pub fn add(config: &Config, add: Add) -> Result<u8, Error> {
 pub struct Remove {
     /// The packages to remove
     packages: Vec<String>,
+    /// How many packages are sent to the coordinator per request
+    #[arg(long, default_value_t = DEFAULT_BATCH_SIZE, value_parser = parse_batch_size)]
+    batch_size: usize,
 }
 
 pub fn remove(config: &Config, remove: Remove) -> Result<u8, Error> {
@@ -79,15 +269,34 @@ pub fn remove(config: &Config, remove: Remove) -> Result<u8, Error> {
         return Ok(1);
     }
 
-    let remove = RemovePackages {
-        packages: remove.packages.into_iter().collect(),
+    let packages: Vec<String> = remove.packages.into_iter().sorted().dedup().collect();
+    let mut response = RemovePackagesResponse {
+        removed: BTreeSet::new(),
+        not_tracked: BTreeSet::new(),
     };
-
-    let response: RemovePackagesResponse = client
-        .post(&endpoints.remove_packages())
-        .send_json(remove)
-        .map_err(Box::new)?
-        .into_json()?;
+    let mut failed = Vec::new();
+    for batch in packages.chunks(remove.batch_size) {
+        let remove_packages = RemovePackages {
+            packages: batch.iter().cloned().collect(),
+        };
+        let result = client
+            .post(&endpoints.remove_packages())
+            .send_json(remove_packages)
+            .map_err(describe_request_error)
+            .and_then(|response| {
+                response
+                    .into_json::<RemovePackagesResponse>()
+                    .map_err(|err| err.to_string())
+            });
+        match result {
+            Ok(batch_response) => {
+                response.removed.extend(batch_response.removed);
+                response.not_tracked.extend(batch_response.not_tracked);
+            }
+            Err(err) => failed.push((batch.to_vec(), err)),
+        }
+    }
+    report_failed_batches("remove", &failed);
 
     if !response.not_tracked.is_empty() {
         let were_was = if response.not_tracked.len() > 1 {
@@ -105,30 +314,307 @@ pub fn remove(config: &Config, remove: Remove) -> Result<u8, Error> {
         Ok(1)
     } else {
         info!("Removed {}", combine_for_display(&response.removed));
+        Ok(u8::from(!failed.is_empty()))
+    }
+}
+
+#[derive(Clone, Args)]
+pub struct Rebuild {
+    /// The packages to rebuild
+    packages: Vec<String>,
+    /// Remove the current builds from the repository before rebuilding
+    #[arg(long)]
+    clean: bool,
+}
+
+pub fn rebuild(config: &Config, rebuild: Rebuild) -> Result<u8, Error> {
+    let client = Agent::new();
+    let endpoints: Endpoints = config.server.to_endpoints();
+
+    if rebuild.packages.is_empty() {
+        error!("No packages to rebuild were given.");
+        return Ok(1);
+    }
+
+    let rebuild = RebuildPackages {
+        packages: rebuild.packages.into_iter().collect(),
+        clean: rebuild.clean,
+    };
+
+    let response: RebuildPackagesResponse = client
+        .post(&endpoints.rebuild_packages())
+        .send_json(rebuild)
+        .map_err(Box::new)?
+        .into_json()?;
+
+    if !response.not_tracked.is_empty() {
+        let are_is = if response.not_tracked.len() > 1 {
+            "are"
+        } else {
+            "is"
+        };
+        warn!(
+            "{} {are_is} not tracked",
+            combine_for_display(&response.not_tracked)
+        );
+    }
+    if response.rebuilding.is_empty() {
+        error!("No changes have been made");
+        Ok(1)
+    } else {
+        info!("Rebuilding {}", combine_for_display(&response.rebuilding));
         Ok(0)
     }
 }
 
-pub fn status(config: &Config) -> Result<u8, Error> {
+#[derive(Clone, Args)]
+pub struct SyncPackages {
+    /// File listing the packages that should be tracked, one per line
+    manifest: PathBuf,
+    /// Also remove tracked packages that are not listed in the manifest
+    #[arg(long)]
+    prune: bool,
+    /// How many packages are sent to the coordinator per request
+    #[arg(long, default_value_t = DEFAULT_BATCH_SIZE, value_parser = parse_batch_size)]
+    batch_size: usize,
+}
+
+pub fn sync(config: &Config, sync: SyncPackages) -> Result<u8, Error> {
     let client = Agent::new();
     let endpoints: Endpoints = config.server.to_endpoints();
 
+    let manifest = read_manifest(&sync.manifest)?;
     let status: Status = client
         .get(&endpoints.status())
         .call()
         .map_err(Box::new)?
         .into_json()?;
 
+    let missing: Vec<String> = manifest.difference(&status.packages).cloned().collect();
+    // Dependencies are managed by the coordinator, so they never count as extras.
+    let extra: Vec<String> = status
+        .packages
+        .difference(&status.dependencies)
+        .filter(|package| !manifest.contains(*package))
+        .cloned()
+        .collect();
+
+    if missing.is_empty() && extra.is_empty() {
+        info!("Tracked packages already match the manifest");
+        return Ok(0);
+    }
+
+    info!("{}", "Plan:".bold());
+    if !missing.is_empty() {
+        info!("Add {}", combine_for_display(&missing));
+    }
+    if !extra.is_empty() {
+        if sync.prune {
+            info!("Remove {}", combine_for_display(&extra));
+        } else {
+            info!(
+                "Keep {}, as they are not in the manifest but --prune was not given",
+                combine_for_display(&extra)
+            );
+        }
+    }
+    info!("");
+
+    let mut exit_code = 0;
+    if !missing.is_empty() {
+        exit_code = exit_code.max(add(
+            config,
+            Add {
+                packages: missing,
+                channel: None,
+                batch_size: sync.batch_size,
+                no_deps: false,
+                no_build: false,
+            },
+        )?);
+    }
+    if sync.prune && !extra.is_empty() {
+        let remove_extra = Remove {
+            packages: extra,
+            batch_size: sync.batch_size,
+        };
+        exit_code = exit_code.max(remove(config, remove_extra)?);
+    }
+
+    Ok(exit_code)
+}
+
+/// Reads a manifest file. Packages are separated by whitespace and everything after a `#` is
+/// treated as a comment.
+fn read_manifest(path: &Path) -> Result<BTreeSet<String>, std::io::Error> {
+    Ok(read_to_string(path)?
+        .lines()
+        .filter_map(|line| line.split('#').next())
+        .flat_map(str::split_whitespace)
+        .map(String::from)
+        .collect())
+}
+
+#[derive(Clone, Args)]
+pub struct Export {
+    /// How to print the packages
+    #[arg(long, value_enum, default_value_t = ExportFormat::Manifest)]
+    format: ExportFormat,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    /// One package per line, as read by `archie sync`
+    Manifest,
+    /// A `depends` array to paste into the PKGBUILD of a meta-package
+    Bash,
+}
+
+/// Prints the packages that were added to the coordinator. Dependencies are left out, as they are
+/// tracked again when the packages get added.
+pub fn export(config: &Config, export: Export) -> Result<u8, Error> {
+    let client = Agent::new();
+    let endpoints: Endpoints = config.server.to_endpoints();
+
+    let status: Status = client
+        .get(&endpoints.status())
+        .call()
+        .map_err(Box::new)?
+        .into_json()?;
+    // Local packages can't be installed from the AUR, so they are left out.
+    let packages = status
+        .packages
+        .iter()
+        .filter(|package| {
+            !status.dependencies.contains(*package) && !status.local.contains(*package)
+        })
+        .map(String::as_str);
+
+    match export.format {
+        ExportFormat::Manifest => packages.for_each(|package| println!("{package}")),
+        ExportFormat::Bash => println!("{}", bash_array("depends", packages)),
+    }
+    Ok(0)
+}
+
+#[derive(Clone, Args)]
+pub struct StatusArgs {
+    /// How to print the status
+    #[arg(long, value_enum, default_value_t = StatusFormat::Text)]
+    format: StatusFormat,
+    /// Keep refreshing the status until interrupted
+    #[arg(long, conflicts_with = "format")]
+    watch: bool,
+    /// Only show the packages matching the glob pattern, like 'python-*'
+    #[arg(long, value_name = "PATTERN")]
+    filter: Option<String>,
+    /// Only print how many packages there are in each state
+    #[arg(long, conflicts_with_all = ["format", "watch"])]
+    count: bool,
+    /// Read the state file in the coordinator's STATE_DIR instead of asking the coordinator, for
+    /// when it isn't running
+    #[arg(
+        long,
+        value_name = "STATE_DIR",
+        num_args = 0..=1,
+        default_missing_value = "/config",
+        conflicts_with_all = ["format", "watch", "filter", "count"]
+    )]
+    local: Option<PathBuf>,
+}
+
+impl StatusArgs {
+    /// The local status is read without the coordinator, so it doesn't need a config.
+    pub fn is_local(&self) -> bool {
+        self.local.is_some()
+    }
+}
+
+/// How often `status --watch` refreshes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, ValueEnum)]
+enum StatusFormat {
+    Text,
+    /// A single line and exit code for Nagios and compatible monitoring systems
+    Nagios,
+}
+
+pub fn status(config: &Config, args: StatusArgs) -> Result<u8, Error> {
+    if let Some(state_dir) = &args.local {
+        return print_local_status(state_dir);
+    }
+    let filter = args.filter.as_deref();
+    if args.count {
+        return print_status_counts(config, filter);
+    }
+    if !args.watch {
+        return print_status(config, args.format, filter);
+    }
+    loop {
+        // Clears the terminal and moves the cursor to the top.
+        print!("\x1b[2J\x1b[H");
+        if let Err(err) = print_status(config, StatusFormat::Text, filter) {
+            error!("{err}");
+        }
+        std::thread::sleep(WATCH_INTERVAL);
+    }
+}
+
+/// Asks for the status, leaving out the packages that don't match the filter.
+fn status_request(config: &Config, filter: Option<&str>) -> ureq::Request {
+    let endpoints: Endpoints = config.server.to_endpoints();
+    let request = Agent::new().get(&endpoints.status());
+    match filter {
+        Some(filter) => request.query("filter", filter),
+        None => request,
+    }
+}
+
+fn print_status_counts(config: &Config, filter: Option<&str>) -> Result<u8, Error> {
+    let counts: StatusCounts = status_request(config, filter)
+        .query("count_only", "true")
+        .call()
+        .map_err(Box::new)?
+        .into_json()?;
+
+    info!("Tracked packages: {}", counts.packages);
+    info!("Dependencies: {}", counts.dependencies);
+    info!("Building: {}", counts.building);
+    info!("Failing: {}", counts.failing);
+    info!("Quarantined: {}", counts.quarantined);
+    info!("Flagged as out of date: {}", counts.out_of_date);
+    Ok(0)
+}
+
+fn print_status(config: &Config, format: StatusFormat, filter: Option<&str>) -> Result<u8, Error> {
+    let status = status_request(config, filter)
+        .call()
+        .map_err(|err| Error::from(Box::new(err)))
+        .and_then(|response| Ok(response.into_json::<Status>()?));
+    let status = match (status, format) {
+        (Ok(status), StatusFormat::Text) => status,
+        (Ok(status), StatusFormat::Nagios) => return Ok(print_nagios_status(&status)),
+        (Err(err), StatusFormat::Text) => return Err(err),
+        (Err(err), StatusFormat::Nagios) => {
+            println!("ARCHIE UNKNOWN - Failed to query the coordinator: {err}");
+            return Ok(NAGIOS_UNKNOWN);
+        }
+    };
+
     let mut warnings = Vec::new();
     let package_text_block = wrap_text(&combine_for_display(&status.packages), 80);
 
     match check_for_repository(config) {
         Ok(true) => (),
         Ok(false) => {
-            warnings.push("Your pacman.conf does not seem to contain the servers repository");
+            warnings.push(
+                "Your pacman.conf does not seem to contain the servers repository".to_string(),
+            );
         }
-        Err(_) => warnings
-            .push("Could not check if pacman is set up correctly. Could not read pacman.conf"),
+        Err(_) => warnings.push(
+            "Could not check if pacman is set up correctly. Could not read pacman.conf".to_string(),
+        ),
     };
 
     info!(
@@ -136,7 +622,46 @@ pub fn status(config: &Config) -> Result<u8, Error> {
         config.server.address.green(),
         config.server.port.to_string().green()
     );
+    if let Some(worker_version) = &status.worker_version {
+        info!("Last seen worker version: {}", worker_version.green());
+    }
+    let repository = &status.repository;
+    let free_space = repository
+        .free_space
+        .map_or_else(|| "unknown".to_string(), format_size);
+    info!(
+        "Repository: {} in {} package files, {free_space} free",
+        format_size(repository.size),
+        repository.package_files
+    );
     info!("");
+    if status.docker_unavailable {
+        warnings.push("The coordinator can not reach Docker, so no packages get built".to_string());
+    }
+    if status.paused {
+        warnings.push("Builds are paused. Use 'archie resume' to start building again".to_string());
+    }
+    if status.outside_build_window {
+        warnings.push("Waiting for the build window, new builds start once it opens".to_string());
+    }
+    if !status.quarantined.is_empty() {
+        warnings.push(format!(
+            "Quarantined after failing repeatedly: {}. Use 'archie rebuild' to try again",
+            combine_for_display(&status.quarantined)
+        ));
+    }
+    if !status.evicted.is_empty() {
+        warnings.push(format!(
+            "Evicted to stay below REPO_MAX_SIZE: {}. Use 'archie rebuild' to add them again",
+            combine_for_display(&status.evicted)
+        ));
+    }
+    if !status.out_of_date.is_empty() {
+        warnings.push(format!(
+            "Flagged as out of date in the AUR: {}",
+            combine_for_display(&status.out_of_date)
+        ));
+    }
     if warnings.is_empty() {
         info!("No issues found!");
     } else {
@@ -145,6 +670,21 @@ pub fn status(config: &Config) -> Result<u8, Error> {
             warn!("{warning}");
         }
     }
+    if !status.building.is_empty() {
+        info!("");
+        info!("{}", "Building:".bold());
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        for package in status.building.iter().sorted() {
+            match status.progress.get(package) {
+                Some(progress) => info!(
+                    "{package}: {} ({} elapsed)",
+                    progress.stage,
+                    format_duration(now - progress.started)
+                ),
+                None => info!("{package}: starting"),
+            }
+        }
+    }
     info!("");
     info!("{}", "Tracked packages:".bold());
     info!("{package_text_block}");
@@ -152,6 +692,454 @@ pub fn status(config: &Config) -> Result<u8, Error> {
     Ok(0)
 }
 
+/// The parts of the coordinator's state file the local status shows.
+#[derive(Deserialize)]
+struct LocalState {
+    package_status: HashMap<String, LocalPackage>,
+}
+
+#[derive(Deserialize)]
+struct LocalPackage {
+    is_dependency: bool,
+    build: Option<LocalBuild>,
+    #[serde(default)]
+    quarantined_since: Option<i64>,
+    #[serde(default)]
+    last_failure: Option<LocalFailure>,
+}
+
+#[derive(Deserialize)]
+struct LocalBuild {
+    time: i64,
+}
+
+#[derive(Deserialize)]
+struct LocalFailure {
+    time: i64,
+}
+
+/// Prints the tracked packages and their builds straight from the state file. The coordinator
+/// only writes it when something changes, so running builds and update checks are missing.
+fn print_local_status(state_dir: &Path) -> Result<u8, Error> {
+    let path = state_dir.join("state.json");
+    let contents = match read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            error!("Failed to read {}: {err}", path.display());
+            return Ok(1);
+        }
+    };
+    let state: LocalState = match serde_json::from_str(&contents) {
+        Ok(state) => state,
+        Err(err) => {
+            error!("Failed to parse {}: {err}", path.display());
+            return Ok(1);
+        }
+    };
+
+    warn!(
+        "{}",
+        format!(
+            "Offline status read from {}, not from a running coordinator",
+            path.display()
+        )
+        .bold()
+    );
+    info!("");
+    for (name, package) in state.package_status.iter().sorted_by_key(|(name, _)| *name) {
+        let name = if package.is_dependency {
+            format!("{name} (dependency)")
+        } else {
+            name.clone()
+        };
+        let build = package.build.as_ref().map_or_else(
+            || "not built".to_string(),
+            |build| format!("built {}", format_timestamp(build.time)),
+        );
+        let mut problems = Vec::new();
+        if let Some(failure) = &package.last_failure {
+            problems.push(format!("failed {}", format_timestamp(failure.time)));
+        }
+        if let Some(since) = package.quarantined_since {
+            problems.push(format!("quarantined since {}", format_timestamp(since)));
+        }
+        if problems.is_empty() {
+            info!("{name}: {build}");
+        } else {
+            warn!("{name}: {build}, {}", problems.join(", "));
+        }
+    }
+    info!("");
+    info!("{} packages tracked", state.package_status.len());
+
+    Ok(0)
+}
+
+const NAGIOS_OK: u8 = 0;
+const NAGIOS_WARNING: u8 = 1;
+const NAGIOS_CRITICAL: u8 = 2;
+const NAGIOS_UNKNOWN: u8 = 3;
+
+/// Prints the status as a single line with performance data, returning the exit code. Quarantined
+/// packages and an unreachable Docker are critical, paused builds and packages that failed but get
+/// retried a warning.
+fn print_nagios_status(status: &Status) -> u8 {
+    let mut problems = Vec::new();
+    if status.docker_unavailable {
+        problems.push("Docker is unreachable".to_string());
+    }
+    if !status.quarantined.is_empty() {
+        problems.push(format!(
+            "quarantined: {}",
+            status.quarantined.iter().sorted().join(", ")
+        ));
+    }
+    let critical = !problems.is_empty();
+    if status.paused {
+        problems.push("builds are paused".to_string());
+    }
+    if !status.failing.is_empty() {
+        problems.push(format!(
+            "failing: {}",
+            status.failing.iter().sorted().join(", ")
+        ));
+    }
+
+    let (label, exit_code) = match (critical, problems.is_empty()) {
+        (true, _) => ("CRITICAL", NAGIOS_CRITICAL),
+        (false, false) => ("WARNING", NAGIOS_WARNING),
+        (false, true) => ("OK", NAGIOS_OK),
+    };
+    let summary = if problems.is_empty() {
+        format!("{} packages tracked", status.packages.len())
+    } else {
+        problems.join("; ")
+    };
+    println!(
+        "ARCHIE {label} - {summary} | tracked={} building={} failing={} quarantined={}",
+        status.packages.len(),
+        status.building.len(),
+        status.failing.len(),
+        status.quarantined.len()
+    );
+    exit_code
+}
+
+#[derive(Clone, Args)]
+pub struct Info {
+    /// The package to show information about
+    package: String,
+}
+
+pub fn info(config: &Config, info: Info) -> Result<u8, Error> {
+    let client = Agent::new();
+    let endpoints: Endpoints = config.server.to_endpoints();
+
+    let details: PackageDetails = client
+        .get(&endpoints.package(&info.package))
+        .call()
+        .map_err(Box::new)?
+        .into_json()?;
+
+    let tracked_as = if details.is_dependency {
+        "Dependency"
+    } else {
+        "Explicitly added"
+    };
+    let dependencies = if details.dependencies.is_empty() {
+        "None".to_string()
+    } else {
+        combine_for_display(&details.dependencies)
+    };
+
+    info!("{}", details.name.bold());
+    info!("Tracked as:   {tracked_as}");
+    info!("Source:       {}", details.source);
+    if !details.channel.is_empty() {
+        info!("Channel:      {}", details.channel);
+    }
+    info!("Dependencies: {dependencies}");
+    match details.build {
+        Some(build) => {
+            info!("Last build:   {}", format_timestamp(build.time));
+            if let Some(commit) = build.commit {
+                info!("Commit:       {commit}");
+            }
+            info!("Files:");
+            for file in build.files {
+                match build.checksums.get(&file) {
+                    Some(checksum) => info!("  {file} (sha256 {checksum})"),
+                    None => info!("  {file}"),
+                }
+            }
+        }
+        None => info!("Last build:   Never"),
+    }
+    let mut promoted: Vec<_> = details.promoted.into_iter().collect();
+    promoted.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (channel, build) in promoted {
+        info!(
+            "Promoted:     to {channel}, the build from {}",
+            format_timestamp(build.time)
+        );
+    }
+    if let Some(flagged) = details.out_of_date {
+        warn!(
+            "Out of date:  flagged in the AUR on {}",
+            format_timestamp(flagged)
+        );
+    }
+    if let Some(since) = details.quarantined_since {
+        warn!(
+            "Quarantined:  since {}, use 'archie rebuild' to try again",
+            format_timestamp(since)
+        );
+    }
+    if let Some(failure) = details.last_failure {
+        let exit = failure
+            .exit_code
+            .map_or("was terminated by a signal".to_string(), |code| {
+                format!("exited with code {code}")
+            });
+        info!(
+            "Last failure: {}, {}",
+            format_timestamp(failure.time),
+            failure.reason
+        );
+        info!("  '{}' {exit}", failure.command);
+        for line in failure.output {
+            info!("  {line}");
+        }
+    }
+    if let Some(memory_limit) = details.overrides.memory_limit {
+        info!("Memory limit: {memory_limit} bytes");
+    }
+    if !details.overrides.extra_build_args.is_empty() {
+        info!(
+            "Build args:   {}",
+            details.overrides.extra_build_args.join(" ")
+        );
+    }
+    if !details.overrides.answers.is_empty() {
+        info!("Answers:      {}", details.overrides.answers.join(", "));
+    }
+    if !details.overrides.pkgbuild_additions.is_empty() {
+        info!("PKGBUILD additions:");
+        for line in &details.overrides.pkgbuild_additions {
+            info!("  {line}");
+        }
+    }
+
+    Ok(0)
+}
+
+#[derive(Clone, Args)]
+pub struct Override {
+    /// The package to change the build settings of
+    package: String,
+    /// Memory limit of the build container, e.g. 16G. Uses the coordinator's default when omitted
+    #[arg(long, value_parser = parse_size)]
+    memory_limit: Option<i64>,
+    /// Argument passed to paru instead of the coordinator's EXTRA_BUILD_ARGS. Can be repeated
+    #[arg(long = "build-arg", allow_hyphen_values = true)]
+    build_args: Vec<String>,
+    /// Removes all overrides before setting the passed ones. Others keep their value otherwise
+    #[arg(long)]
+    reset: bool,
+    /// Line written to the input of the build, answering a prompt. Can be repeated
+    #[arg(long = "answer", allow_hyphen_values = true)]
+    answers: Vec<String>,
+    /// Line appended to the PKGBUILD before building, e.g. 'checkdepends=()'. Can be repeated
+    #[arg(long = "pkgbuild-line", allow_hyphen_values = true)]
+    pkgbuild_additions: Vec<String>,
+}
+
+pub fn set_overrides(config: &Config, overrides: Override) -> Result<u8, Error> {
+    let client = Agent::new();
+    let endpoints: Endpoints = config.server.to_endpoints();
+
+    client
+        .post(&endpoints.package_overrides(&overrides.package))
+        .send_json(OverridesUpdate {
+            reset: overrides.reset,
+            memory_limit: overrides.memory_limit,
+            extra_build_args: passed(overrides.build_args),
+            answers: passed(overrides.answers),
+            pkgbuild_additions: passed(overrides.pkgbuild_additions),
+        })
+        .map_err(Box::new)?;
+
+    info!("Updated the build settings of {}", overrides.package);
+    Ok(0)
+}
+
+/// Repeatable flags that weren't passed at all are left out of the update.
+fn passed(values: Vec<String>) -> Option<Vec<String>> {
+    (!values.is_empty()).then_some(values)
+}
+
+#[derive(Clone, Args)]
+pub struct Promote {
+    /// The package to promote
+    package: String,
+    /// The channel to take the current build from
+    #[arg(long)]
+    from: String,
+    /// The channel to copy the build into
+    #[arg(long)]
+    to: String,
+}
+
+pub fn promote(config: &Config, promote: Promote) -> Result<u8, Error> {
+    let client = Agent::new();
+    let endpoints: Endpoints = config.server.to_endpoints();
+
+    client
+        .post(&endpoints.promote_package(&promote.package))
+        .send_json(PromotePackage {
+            from: promote.from.clone(),
+            to: promote.to.clone(),
+        })
+        .map_err(Box::new)?;
+
+    info!(
+        "Promoting {} from {} to {}",
+        promote.package, promote.from, promote.to
+    );
+    Ok(0)
+}
+
+#[derive(Clone, Args)]
+pub struct Rdeps {
+    /// The package to list the dependents of
+    package: String,
+}
+
+pub fn reverse_dependencies(config: &Config, rdeps: Rdeps) -> Result<u8, Error> {
+    let client = Agent::new();
+    let endpoints: Endpoints = config.server.to_endpoints();
+
+    let response: ReverseDependencies = client
+        .get(&endpoints.reverse_dependencies(&rdeps.package))
+        .call()
+        .map_err(Box::new)?
+        .into_json()?;
+
+    if response.required_by.is_empty() {
+        info!("No tracked package depends on {}", response.name);
+    } else {
+        info!(
+            "{} is required by {}",
+            response.name,
+            combine_for_display(&response.required_by)
+        );
+    }
+
+    Ok(0)
+}
+
+#[derive(Clone, Args)]
+pub struct Deps {
+    /// The package to list the dependencies of
+    package: String,
+    /// Show which package pulled in which dependency
+    #[arg(long)]
+    tree: bool,
+}
+
+pub fn dependencies(config: &Config, deps: Deps) -> Result<u8, Error> {
+    let client = Agent::new();
+    let endpoints: Endpoints = config.server.to_endpoints();
+
+    let response: DependencyClosure = client
+        .get(&endpoints.dependency_closure(&deps.package))
+        .call()
+        .map_err(Box::new)?
+        .into_json()?;
+
+    if response.closure.is_empty() {
+        info!("{} has no AUR dependencies", response.name);
+    } else if deps.tree {
+        let mut shown = HashSet::new();
+        print_tree(&response.name, 0, &response.dependencies, &mut shown);
+    } else {
+        info!(
+            "{} depends on {} packages, in build order:",
+            response.name,
+            response.closure.len()
+        );
+        info!("{}", wrap_text(&response.closure.join(", "), 80));
+    }
+
+    Ok(0)
+}
+
+/// Prints the package and its dependencies below it. Packages that were shown already aren't
+/// expanded again, which also stops cycles.
+fn print_tree(
+    package: &str,
+    depth: usize,
+    dependencies: &BTreeMap<String, Vec<String>>,
+    shown: &mut HashSet<String>,
+) {
+    let indent = "  ".repeat(depth);
+    if !shown.insert(package.to_string()) {
+        info!("{indent}{package} {}", "(see above)".dimmed());
+        return;
+    }
+
+    info!("{indent}{package}");
+    for dependency in dependencies.get(package).into_iter().flatten() {
+        print_tree(dependency, depth + 1, dependencies, shown);
+    }
+}
+
+pub fn reset_failures(config: &Config) -> Result<u8, Error> {
+    let client = Agent::new();
+    let endpoints: Endpoints = config.server.to_endpoints();
+
+    client
+        .post(&endpoints.reset_failures())
+        .call()
+        .map_err(Box::new)?;
+
+    info!("Cleared the last failures and released all packages from quarantine");
+    Ok(0)
+}
+
+pub fn check_updates(config: &Config) -> Result<u8, Error> {
+    let client = Agent::new();
+    let endpoints: Endpoints = config.server.to_endpoints();
+
+    client
+        .post(&endpoints.check_updates())
+        .call()
+        .map_err(Box::new)?;
+
+    info!("The coordinator is checking the AUR for updates");
+    Ok(0)
+}
+
+pub fn pause(config: &Config) -> Result<u8, Error> {
+    let client = Agent::new();
+    let endpoints: Endpoints = config.server.to_endpoints();
+
+    client.post(&endpoints.pause()).call().map_err(Box::new)?;
+
+    info!("The coordinator won't start new builds until 'archie resume' is run");
+    Ok(0)
+}
+
+pub fn resume(config: &Config) -> Result<u8, Error> {
+    let client = Agent::new();
+    let endpoints: Endpoints = config.server.to_endpoints();
+
+    client.post(&endpoints.resume()).call().map_err(Box::new)?;
+
+    info!("The coordinator is building packages again");
+    Ok(0)
+}
+
 fn check_for_repository(config: &Config) -> Result<bool, std::io::Error> {
     let pacman_conf = read_to_string("/etc/pacman.conf")?;
     let port = if config.server.port == 80 && !config.server.https
@@ -162,7 +1150,43 @@ fn check_for_repository(config: &Config) -> Result<bool, std::io::Error> {
         format!(":{}", config.server.port)
     };
 
-    let directive = format!("{}{port}/repo", config.server.address);
+    let base_path = config.server.base_path.trim_matches('/');
+    let base_path = if base_path.is_empty() {
+        String::new()
+    } else {
+        format!("/{base_path}")
+    };
+    let directive = format!("{}{port}{base_path}/repo", config.server.address);
 
     Ok(pacman_conf.lines().any(|line| line.contains(&directive)))
 }
+
+#[derive(Clone, Subcommand)]
+pub enum ConfigCommand {
+    /// Show the config the coordinator loaded, without its secrets
+    Show,
+}
+
+pub fn coordinator_config(config: &Config, command: ConfigCommand) -> Result<u8, Error> {
+    let client = Agent::new();
+    let endpoints: Endpoints = config.server.to_endpoints();
+
+    match command {
+        ConfigCommand::Show => {
+            let values: serde_json::Map<String, serde_json::Value> = client
+                .get(&endpoints.config())
+                .call()
+                .map_err(Box::new)?
+                .into_json()?;
+            for (key, value) in values {
+                let value = match value {
+                    serde_json::Value::String(value) => value,
+                    serde_json::Value::Null => "not set".to_string(),
+                    value => value.to_string(),
+                };
+                info!("{key}: {value}");
+            }
+        }
+    }
+    Ok(0)
+}
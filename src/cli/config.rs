@@ -26,6 +26,9 @@ pub struct Server {
     pub port: u16,
     /// Use HTTP instead of HTTPS
     pub https: bool,
+    /// Path the server is reachable under, if it is behind a reverse proxy
+    #[serde(default)]
+    pub base_path: String,
 }
 
 impl Server {
@@ -34,6 +37,7 @@ impl Server {
             address: self.address.clone(),
             port: self.port,
             https: self.https,
+            base_path: self.base_path.clone(),
         }
     }
 }
@@ -44,6 +48,7 @@ impl Default for Server {
             port: 3200,
             address: "localhost".to_string(),
             https: false,
+            base_path: String::new(),
         }
     }
 }
@@ -118,6 +123,16 @@ pub fn init(config: &mut Config, profile: &str) -> Result<u8, Error> {
         }
     }
 
+    println!("Under which path is the coordinator served? Leave empty unless it is behind a reverse proxy");
+    print!("Path [{}]: ", config.server.base_path);
+    stdout.flush()?;
+    let mut buffer = String::new();
+    stdin.read_line(&mut buffer)?;
+    let buffer = buffer.trim();
+    if !buffer.is_empty() {
+        config.server.base_path = buffer.trim_matches('/').to_string();
+    }
+
     println!("Use HTTPS?");
     loop {
         let current = if config.server.https { "Y" } else { "N" };
@@ -4,7 +4,10 @@ use tracing_subscriber::fmt::FormatEvent;
 use tracing_subscriber::fmt::{self, format::Writer};
 use tracing_subscriber::{fmt::format::FormatFields, registry::LookupSpan};
 
-pub struct ColorFormatter;
+pub struct ColorFormatter {
+    /// Whether to emit ANSI escape codes
+    pub color: bool,
+}
 
 impl<S, N> FormatEvent<S, N> for ColorFormatter
 where
@@ -22,6 +25,7 @@ where
 
         // Determine color based on level
         let color = match *level {
+            _ if !self.color => "",
             Level::ERROR => "\x1b[91m", // Red
             Level::WARN => "\x1b[93m",  // Yellow
             Level::INFO => "",          // Nothing
@@ -32,7 +36,7 @@ where
         ctx.field_format();
 
         // Reset color
-        let reset = "\x1b[0m";
+        let reset = if self.color { "\x1b[0m" } else { "" };
 
         let mut message = String::new();
         ctx.format_fields(Writer::new(&mut message), event)?;
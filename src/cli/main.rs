@@ -4,80 +4,176 @@ mod log_formatter;
 mod util;
 
 use crate::log_formatter::ColorFormatter;
-use clap::{Parser, Subcommand};
+use clap::{ArgAction, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use std::env::var_os;
+use std::io::stdout;
 use std::process::ExitCode;
 use thiserror::Error;
 use tracing::{error, Level};
 use tracing_subscriber::FmtSubscriber;
 use ureq::ErrorKind;
-use coordinator::print_version;
+use coordinator::{print_version, ErrorResponse};
 
 #[derive(Parser)]
 struct Arguments {
     #[command(subcommand)]
-    action: Action,
+    command: Command,
     /// Name of the profile to use
     #[arg(long, default_value = "config")]
     profile: String,
+    /// Print plain text without colors. Also enabled by setting NO_COLOR
+    #[arg(long, global = true)]
+    no_color: bool,
+    /// Print more details. Use twice to print everything
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+}
+
+/// Completions are printed before the config is loaded, so they work without one.
+#[derive(Subcommand, Clone)]
+enum Command {
+    #[command(flatten)]
+    Action(Action),
+    /// Print a completion script for the given shell
+    Completions { shell: Shell },
 }
 
 #[derive(Subcommand, Clone)]
 enum Action {
     /// Add new packages to the coordinator
     Add(actions::Add),
+    /// Build a package from a local PKGBUILD, uploading it again replaces the previous sources
+    AddLocal(actions::AddLocal),
     /// Remove packages from the coordinator
     Remove(actions::Remove),
+    /// Build packages right away, releasing them from quarantine
+    Rebuild(actions::Rebuild),
+    /// Copy the current build of a package from one channel into another
+    Promote(actions::Promote),
+    /// Forget the failures of all packages and release them from quarantine
+    ResetFailures,
+    /// Check the AUR for updates of the tracked packages right away
+    Check,
+    /// Stop starting new builds, running builds still finish
+    Pause,
+    /// Start building again after a pause
+    Resume,
+    /// Add and remove packages so the tracked packages match a manifest file
+    Sync(actions::SyncPackages),
+    /// Print the added packages, for a manifest file or a meta-package
+    Export(actions::Export),
+    /// Show detailed information about a tracked package
+    Info(actions::Info),
+    /// Override the build settings of a package. Omitted settings keep their value
+    Override(actions::Override),
+    /// List the tracked packages that depend on a package
+    Rdeps(actions::Rdeps),
+    /// List every package a package depends on, directly or indirectly
+    Deps(actions::Deps),
     /// Display the status of coordinator
-    Status,
+    Status(actions::StatusArgs),
     /// Setup archie's config
     Init,
+    /// Inspect the config of the coordinator
+    #[command(subcommand)]
+    Config(actions::ConfigCommand),
     /// Print version info
     Version,
 }
 
 fn main() -> Result<ExitCode, Error> {
+    let args = Arguments::parse();
+
+    let color = !args.no_color && var_os("NO_COLOR").is_none_or(|value| value.is_empty());
+    if !color {
+        colored::control::set_override(false);
+    }
+
     let subscriber = FmtSubscriber::builder()
-        .event_format(ColorFormatter)
-        .with_max_level(Level::TRACE) // Log all levels
+        .event_format(ColorFormatter { color })
+        .with_max_level(log_level(args.verbose))
         .finish();
 
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
 
-    let args = Arguments::parse();
+    let action = match args.command {
+        Command::Action(action) => action,
+        Command::Completions { shell } => {
+            clap_complete::generate(shell, &mut Arguments::command(), "archie", &mut stdout());
+            return Ok(ExitCode::SUCCESS);
+        }
+    };
 
     let mut config = config::load(&args.profile);
 
-    if !config.initialized && !matches!(args.action, Action::Init) {
+    let needs_config = match &action {
+        Action::Init => false,
+        Action::Status(status) => !status.is_local(),
+        _ => true,
+    };
+    if !config.initialized && needs_config {
         println!("Archie's config is not set up. Run 'archie init' to set it up.");
         return Ok(ExitCode::FAILURE);
     }
 
-    let result = match args.action {
+    let result = match action {
         Action::Add(add) => actions::add(&config, add),
+        Action::AddLocal(add) => actions::add_local(&config, add),
         Action::Remove(remove) => actions::remove(&config, remove),
-        Action::Status => actions::status(&config),
+        Action::Rebuild(rebuild) => actions::rebuild(&config, rebuild),
+        Action::Promote(promote) => actions::promote(&config, promote),
+        Action::ResetFailures => actions::reset_failures(&config),
+        Action::Check => actions::check_updates(&config),
+        Action::Pause => actions::pause(&config),
+        Action::Resume => actions::resume(&config),
+        Action::Sync(sync) => actions::sync(&config, sync),
+        Action::Export(export) => actions::export(&config, export),
+        Action::Info(info) => actions::info(&config, info),
+        Action::Override(overrides) => actions::set_overrides(&config, overrides),
+        Action::Rdeps(rdeps) => actions::reverse_dependencies(&config, rdeps),
+        Action::Deps(deps) => actions::dependencies(&config, deps),
+        Action::Status(status) => actions::status(&config, status),
         Action::Init => config::init(&mut config, &args.profile).map_err(Error::from),
+        Action::Config(command) => actions::coordinator_config(&config, command),
         Action::Version => {
             print_version();
             Ok(0)
-        },
+        }
     };
 
     let exit_code = try_to_interpret_error(result)?;
     Ok(ExitCode::from(exit_code))
 }
 
+fn log_level(verbose: u8) -> Level {
+    match verbose {
+        0 => Level::INFO,
+        1 => Level::DEBUG,
+        _ => Level::TRACE,
+    }
+}
+
 /// Tries to get more information to display to the user from the error.
 fn try_to_interpret_error(result: Result<u8, Error>) -> Result<u8, Error> {
     Ok(match result {
         Ok(exit_code) => exit_code,
-        Err(Error::Request(err)) => match err.kind() {
-            ErrorKind::Dns | ErrorKind::ConnectionFailed | ErrorKind::TooManyRedirects => {
-                let transport = err.into_transport().unwrap();
-                error!("{transport}");
+        Err(Error::Request(err)) => match *err {
+            ureq::Error::Status(code, response) => {
+                match response.into_json::<ErrorResponse>() {
+                    Ok(response) => error!("{}", response.error),
+                    Err(_) => error!("The coordinator responded with status {code}"),
+                }
                 1
             }
-            _ => return Err(Error::Request(err)),
+            err => match err.kind() {
+                ErrorKind::Dns | ErrorKind::ConnectionFailed | ErrorKind::TooManyRedirects => {
+                    let transport = err.into_transport().unwrap();
+                    error!("{transport}");
+                    1
+                }
+                _ => return Err(Error::Request(Box::new(err))),
+            },
         },
         Err(err) => return Err(err),
     })
@@ -1,29 +1,159 @@
+use itertools::Itertools;
+use time::macros::format_description;
+use time::OffsetDateTime;
+
+/// Wraps the text into lines of at most `max_length` characters. Lines break at the last space
+/// that fits, which gets replaced by the line break. Words longer than a line are split.
 pub fn wrap_text(text: &str, max_length: usize) -> String {
-    let mut last_space = 0;
-    let mut last_split = 0;
-    let mut chars = 0;
-    let mut lines: Vec<&str> = Vec::new();
-
-    for (index, char) in text.chars().enumerate() {
-        chars += 1;
-        if char == ' ' {
-            last_space = index;
-        }
-        if chars > max_length {
-            let split = if last_space == last_split {
-                index
-            } else {
-                last_space + 1
-            };
-            lines.push(&text[last_split..split]);
-            lines.push("\n");
-            last_split = split;
-            chars = 0;
-        }
-    }
-    if last_split != text.len() - 1 {
-        lines.push(&text[last_split..]);
-    }
-
-    lines.into_iter().collect()
+    let max_length = max_length.max(1);
+    let chars: Vec<char> = text.chars().collect();
+    let mut lines: Vec<String> = Vec::new();
+
+    let mut start = 0;
+    while chars.len() - start > max_length {
+        // A space right after a full line can be used to break as well.
+        let window = &chars[start..=start + max_length];
+        let line_end = match window.iter().rposition(|char| *char == ' ') {
+            Some(0) | None => {
+                lines.push(chars[start..start + max_length].iter().collect());
+                start += max_length;
+                continue;
+            }
+            Some(space) => start + space,
+        };
+        lines.push(chars[start..line_end].iter().collect());
+        start = line_end + 1;
+    }
+    lines.push(chars[start..].iter().collect());
+
+    lines.join("\n")
+}
+
+pub fn format_timestamp(timestamp: i64) -> String {
+    let format = format_description!("[year]-[month]-[day] [hour]:[minute]:[second] UTC");
+    OffsetDateTime::from_unix_timestamp(timestamp)
+        .ok()
+        .and_then(|time| time.format(format).ok())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+/// Parses a size in bytes, which may be suffixed with K, M, G or T. Negative sizes are rejected.
+pub fn parse_size(size: &str) -> Result<i64, String> {
+    let (number, multiplier) = match size.chars().last().map(|unit| unit.to_ascii_uppercase()) {
+        Some('K') => (&size[..size.len() - 1], 1 << 10),
+        Some('M') => (&size[..size.len() - 1], 1 << 20),
+        Some('G') => (&size[..size.len() - 1], 1 << 30),
+        Some('T') => (&size[..size.len() - 1], 1 << 40),
+        _ => (size, 1),
+    };
+    number
+        .parse::<i64>()
+        .ok()
+        .filter(|number| !number.is_negative())
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("'{size}' is not a valid size"))
+}
+
+/// Formats a size in bytes with the largest binary unit that keeps it above 1.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1 << 10 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Formats a duration in seconds as hours and minutes, or seconds if it is shorter than a minute.
+pub fn format_duration(seconds: i64) -> String {
+    let seconds = seconds.max(0);
+    match (seconds / 3600, seconds % 3600 / 60) {
+        (0, 0) => format!("{seconds}s"),
+        (0, minutes) => format!("{minutes}m"),
+        (hours, minutes) => format!("{hours}h {minutes}m"),
+    }
+}
+
+/// Formats the values as a bash array assignment, like the ones of a PKGBUILD. Values are quoted,
+/// so they must not contain single quotes themselves.
+pub fn bash_array<'a>(name: &str, values: impl IntoIterator<Item = &'a str>) -> String {
+    let values = values
+        .into_iter()
+        .map(|value| format!("'{value}'"))
+        .join(" ");
+    format!("{name}=({values})")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_empty_text() {
+        assert_eq!(wrap_text("", 10), "");
+    }
+
+    #[test]
+    fn wrap_splits_overlong_word() {
+        assert_eq!(wrap_text("abcdefghij", 4), "abcd\nefgh\nij");
+        assert_eq!(wrap_text("ab abcdefghij cd", 4), "ab\nabcd\nefgh\nij\ncd");
+    }
+
+    #[test]
+    fn wrap_text_at_boundary() {
+        assert_eq!(wrap_text("abcd", 4), "abcd");
+        assert_eq!(wrap_text("abcd efgh", 4), "abcd\nefgh");
+        assert_eq!(wrap_text("ab, cd, ef", 6), "ab,\ncd, ef");
+    }
+
+    #[test]
+    fn wrap_keeps_multiple_spaces() {
+        assert_eq!(wrap_text("ab  cd", 10), "ab  cd");
+        assert_eq!(wrap_text("abc  def", 4), "abc \ndef");
+    }
+
+    #[test]
+    fn wrapped_lines_fit() {
+        let text = "linux-zen, visual-studio-code-bin, a, some-really-long-package-name-git, b";
+        let wrapped = wrap_text(text, 12);
+        assert!(wrapped.lines().all(|line| line.chars().count() <= 12));
+        assert_eq!(wrapped.replace([' ', '\n'], ""), text.replace(' ', ""));
+    }
+
+    #[test]
+    fn parse_sizes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("16G"), Ok(16 << 30));
+        assert_eq!(parse_size("2m"), Ok(2 << 20));
+        assert!(parse_size("-1G").is_err());
+        assert!(parse_size("G").is_err());
+    }
+
+    #[test]
+    fn format_sizes() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 << 30), "3.0 GiB");
+    }
+
+    #[test]
+    fn format_durations() {
+        assert_eq!(format_duration(42), "42s");
+        assert_eq!(format_duration(12 * 60 + 5), "12m");
+        assert_eq!(format_duration(3600 + 5 * 60), "1h 5m");
+    }
+
+    #[test]
+    fn format_bash_arrays() {
+        assert_eq!(bash_array("depends", []), "depends=()");
+        assert_eq!(
+            bash_array("depends", ["paru", "python-pip"]),
+            "depends=('paru' 'python-pip')"
+        );
+    }
 }
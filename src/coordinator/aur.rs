@@ -1,46 +1,92 @@
+use crate::config;
 use crate::messages::Package;
 use crate::stop_token::StopToken;
 use itertools::Itertools;
+use reqwest::NoProxy;
 use serde::Deserialize;
 use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
+use std::future::Future;
+use std::path::Path;
 use std::sync::LazyLock;
 use std::time::Duration;
 use thiserror::Error;
 use tokio::sync::RwLock;
-use tracing::{debug, error};
+use tracing::{debug, error, info, warn};
 
-const URL: &str = "https://aur.archlinux.org/rpc/v5/info?";
 const ARG: &str = "arg[]=";
+/// The packages of the official repositories, one per line, within `STATE_DIR`. Kept so dependencies get filtered
+/// correctly right after a restart, before pacman refreshed the cache.
+const PACKAGE_CACHE_FILE: &str = "package_cache";
+const PACKAGE_CACHE_FILE_TEMP: &str = "package_cache.tmp";
+/// How many similar packages get suggested for a package that doesn't exist.
+const MAX_SUGGESTIONS: usize = 3;
+/// Length of the prefix searched for when the whole name matches nothing.
+const SEARCH_PREFIX_LENGTH: usize = 4;
 
-static PACKAGE_CACHE: LazyLock<RwLock<HashSet<Package>>> =
-    LazyLock::new(|| RwLock::new(HashSet::new()));
+static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(build_client);
+static PACKAGE_CACHE: LazyLock<RwLock<HashSet<Package>>> = LazyLock::new(|| {
+    RwLock::new(read_package_cache(
+        &config::state_dir().join(PACKAGE_CACHE_FILE),
+    ))
+});
 
 #[derive(Deserialize)]
 struct AurRPC {
     results: Vec<PackageInfo>,
 }
 
+#[derive(Deserialize)]
+struct AurSearch {
+    results: Vec<SearchResult>,
+}
+
+#[derive(Deserialize)]
+struct SearchResult {
+    #[serde(rename = "Name")]
+    name: String,
+}
+
 #[derive(Deserialize)]
 struct PackageInfo {
     #[serde(rename = "Name")]
     name: String,
     #[serde(rename = "LastModified")]
     last_modified: i64,
-    #[serde(rename = "Depends")]
+    #[serde(rename = "OutOfDate")]
+    out_of_date: Option<i64>,
+    /// Left out by the AUR for packages without dependencies
+    #[serde(rename = "Depends", default)]
     depends: HashSet<Package>,
 }
 
+fn build_client() -> reqwest::Client {
+    let mut builder = reqwest::Client::builder();
+    if let Some(proxy) = config::proxy() {
+        match reqwest::Proxy::all(proxy) {
+            Ok(proxy) => builder = builder.proxy(proxy.no_proxy(NoProxy::from_env())),
+            Err(err) => error!("Failed to set up the proxy: {err}"),
+        }
+    }
+    builder.build().unwrap_or_else(|err| {
+        error!("Failed to build the HTTP client: {err}");
+        std::process::exit(1);
+    })
+}
+
 pub async fn update_non_aur_packages(mut stop_token: StopToken) {
     loop {
         match run_pacman().await {
             Ok(out) => {
-                let cache: HashSet<String> = String::from_utf8_lossy(&out)
-                    .split('\n')
-                    .map(String::from)
-                    .collect();
-                *PACKAGE_CACHE.write().await = cache;
-                debug!("Updated package cache");
+                let cache = parse_package_list(&String::from_utf8_lossy(&out));
+                if cache.is_empty() {
+                    // Filtering with an empty cache would queue builds of every official package.
+                    warn!("pacman listed no packages. Keeping the previous package cache");
+                } else {
+                    save_package_cache(&cache).await;
+                    *PACKAGE_CACHE.write().await = cache;
+                    debug!("Updated package cache");
+                }
             }
             Err(err) => {
                 error!("Failed to update cache: {err}");
@@ -54,6 +100,45 @@ pub async fn update_non_aur_packages(mut stop_token: StopToken) {
     }
 }
 
+fn parse_package_list(list: &str) -> HashSet<Package> {
+    list.lines()
+        .map(str::trim)
+        .filter(|package| !package.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn read_package_cache(path: &Path) -> HashSet<Package> {
+    match std::fs::read_to_string(path) {
+        Ok(list) => {
+            let cache = parse_package_list(&list);
+            info!("Loaded {} packages from the package cache", cache.len());
+            cache
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+        Err(err) => {
+            warn!("Failed to read the package cache: {err}");
+            HashSet::new()
+        }
+    }
+}
+
+async fn save_package_cache(cache: &HashSet<Package>) {
+    let list = cache.iter().sorted().join("\n");
+    let state_dir = config::state_dir();
+    let (file, temp_file) = (
+        state_dir.join(PACKAGE_CACHE_FILE),
+        state_dir.join(PACKAGE_CACHE_FILE_TEMP),
+    );
+    if let Err(err) = tokio::fs::write(&temp_file, list).await {
+        error!("Failed to write the package cache: {err}");
+        return;
+    }
+    if let Err(err) = tokio::fs::rename(&temp_file, &file).await {
+        error!("Failed to replace the package cache: {err}");
+    }
+}
+
 async fn run_pacman() -> Result<Vec<u8>, Error> {
     tokio::process::Command::new("pacman")
         .arg("-Syy")
@@ -66,19 +151,56 @@ async fn run_pacman() -> Result<Vec<u8>, Error> {
         .stdout)
 }
 
-pub async fn get_last_modified<P, S>(packages: P) -> Result<HashMap<String, i64>, Error>
-where
-    P: IntoIterator<Item = S>,
-    S: AsRef<str> + Display,
-{
-    let aur_data = get_package_info(packages).await?;
+/// What the AUR knows about the state of a package.
+#[derive(Clone, Copy, Debug)]
+pub struct AurStatus {
+    pub last_modified: i64,
+    /// When the package got flagged as out of date
+    pub out_of_date: Option<i64>,
+}
+
+/// The parts of the AUR the scheduler relies on, so they can be replaced in tests.
+pub trait AurClient {
+    /// Returns the status of the packages, leaving out the ones the AUR doesn't know.
+    fn package_status(
+        &self,
+        packages: &HashSet<Package>,
+    ) -> impl Future<Output = Result<HashMap<Package, AurStatus>, Error>> + Send;
 
-    let mut last_modified = HashMap::new();
-    for pkg in aur_data {
-        last_modified.insert(pkg.name, pkg.last_modified);
+    /// Returns the AUR dependencies of the packages, leaving out the ones the AUR doesn't know.
+    fn dependencies(
+        &self,
+        packages: &HashSet<Package>,
+    ) -> impl Future<Output = Result<HashMap<Package, HashSet<Package>>, Error>> + Send;
+}
+
+/// Talks to the AUR's RPC interface.
+pub struct RpcClient;
+
+impl AurClient for RpcClient {
+    async fn package_status(
+        &self,
+        packages: &HashSet<Package>,
+    ) -> Result<HashMap<Package, AurStatus>, Error> {
+        Ok(get_package_info(packages)
+            .await?
+            .into_iter()
+            .map(|info| {
+                let status = AurStatus {
+                    last_modified: info.last_modified,
+                    out_of_date: info.out_of_date,
+                };
+                (info.name, status)
+            })
+            .collect())
     }
 
-    Ok(last_modified)
+    async fn dependencies(
+        &self,
+        packages: &HashSet<Package>,
+    ) -> Result<HashMap<Package, HashSet<Package>>, Error> {
+        get_dependencies(packages).await
+    }
 }
 
 pub async fn do_packages_exist<P, S>(packages: P) -> Result<HashSet<Package>, Error>
@@ -97,28 +219,163 @@ where
     P: IntoIterator<Item = S>,
     S: AsRef<str> + Display,
 {
-    let cache = PACKAGE_CACHE.read().await;
     let info = get_package_info(packages).await?;
-    Ok(info
-        .into_iter()
+    let build_repo_deps = config::build_repo_deps();
+    // Only lock the cache once the AUR answered, so updating it doesn't wait on the request.
+    let cache = PACKAGE_CACHE.read().await;
+    Ok(dependency_map(info, &cache, build_repo_deps))
+}
+
+/// Maps every package the AUR returned to its dependencies, which might be none.
+fn dependency_map(
+    info: Vec<PackageInfo>,
+    cache: &HashSet<Package>,
+    build_repo_deps: bool,
+) -> HashMap<Package, HashSet<Package>> {
+    info.into_iter()
         .map(|info| {
-            (
-                info.name,
-                info.depends
-                    .into_iter()
-                    .filter_map(|pkg| {
-                        if cache.contains(&pkg) || pkg.contains(['<', '>', '=']) {
-                            None
-                        } else {
-                            Some(pkg)
-                        }
-                    })
-                    .collect(),
-            )
+            let dependencies = aur_dependencies(info.depends, cache, build_repo_deps);
+            (info.name, dependencies)
+        })
+        .collect()
+}
+
+/// Leaves out the dependencies with version constraints and, unless `build_repo_deps` is set, the
+/// ones in the official repositories.
+fn aur_dependencies(
+    depends: HashSet<Package>,
+    cache: &HashSet<Package>,
+    build_repo_deps: bool,
+) -> HashSet<Package> {
+    depends
+        .into_iter()
+        .filter(|pkg| {
+            let in_repos = !build_repo_deps && cache.contains(pkg);
+            !in_repos && !pkg.contains(['<', '>', '='])
         })
+        .collect()
+}
+
+/// Looks up the packages and, recursively, their AUR dependencies that aren't known yet. Maps each
+/// of them the AUR knows to its dependencies, so a package missing from the map wasn't found.
+pub async fn dependency_graph<A: AurClient>(
+    aur: &A,
+    packages: &HashSet<Package>,
+    known: &HashSet<Package>,
+) -> Result<HashMap<Package, HashSet<Package>>, Error> {
+    let mut graph = aur.dependencies(packages).await?;
+    let mut looked_up = packages.clone();
+    loop {
+        let unresolved: HashSet<Package> = graph
+            .values()
+            .flatten()
+            .filter(|dep| !known.contains(*dep) && !looked_up.contains(*dep))
+            .cloned()
+            .collect();
+        if unresolved.is_empty() {
+            return Ok(graph);
+        }
+
+        // Dependencies the AUR doesn't know are left out of the graph.
+        graph.extend(aur.dependencies(&unresolved).await?);
+        looked_up.extend(unresolved);
+    }
+}
+
+/// Collects the package and the dependencies in the graph it pulls in, leaving out the known ones.
+pub fn dependency_closure(
+    package: &Package,
+    graph: &HashMap<Package, HashSet<Package>>,
+    known: &HashSet<Package>,
+) -> HashSet<Package> {
+    let mut closure = HashSet::from([package.clone()]);
+    let mut unvisited = vec![package];
+    while let Some(next) = unvisited.pop() {
+        for dependency in graph.get(next).into_iter().flatten() {
+            if graph.contains_key(dependency)
+                && !known.contains(dependency)
+                && closure.insert(dependency.clone())
+            {
+                unvisited.push(dependency);
+            }
+        }
+    }
+    closure
+}
+
+/// Searches the AUR for packages with names similar to the ones that don't exist. Only meant as a
+/// hint, so failed searches are logged and leave the package without suggestions.
+pub async fn suggest_packages(missing: &HashSet<Package>) -> HashMap<Package, Vec<Package>> {
+    let mut suggestions = HashMap::new();
+    for package in missing {
+        match search_similar(package).await {
+            Ok(similar) if !similar.is_empty() => {
+                suggestions.insert(package.clone(), similar);
+            }
+            Ok(_) => (),
+            Err(err) => warn!("Failed to search the AUR for packages like {package}: {err}"),
+        }
+    }
+    suggestions
+}
+
+/// Searches for the longest part of the name, as a typo in any other part would still match it.
+/// If even that has a typo, the start of it is searched for instead.
+async fn search_similar(package: &str) -> Result<Vec<Package>, Error> {
+    let Some(term) = package
+        .split(['-', '_', '.'])
+        .max_by_key(|part| part.len())
+        .filter(|part| part.len() >= 2)
+    else {
+        return Ok(Vec::new());
+    };
+    let mut similar = closest_matches(package, search_names(term).await?);
+    if similar.is_empty() && term.len() > SEARCH_PREFIX_LENGTH {
+        let prefix = &term[..SEARCH_PREFIX_LENGTH];
+        similar = closest_matches(package, search_names(prefix).await?);
+    }
+    Ok(similar)
+}
+
+async fn search_names(term: &str) -> Result<Vec<Package>, Error> {
+    let url = format!("{}/search/{term}?by=name", config::aur_rpc_url());
+    let response = CLIENT.get(&url).send().await?.text().await?;
+    let search: AurSearch = serde_json::de::from_str(&response)?;
+    Ok(search
+        .results
+        .into_iter()
+        .map(|result| result.name)
         .collect())
 }
 
+/// Picks the candidates that are only a few edits away from the name, closest first.
+fn closest_matches(name: &str, candidates: Vec<Package>) -> Vec<Package> {
+    let max_distance = (name.chars().count() / 3).max(2);
+    candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(name, &candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .sorted()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, candidate)| candidate)
+        .collect()
+}
+
+/// The Levenshtein distance between the two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
 async fn get_package_info<P, S>(packages: P) -> Result<Vec<PackageInfo>, Error>
 where
     P: IntoIterator<Item = S>,
@@ -128,9 +385,9 @@ where
         .into_iter()
         .map(|package| format!("{ARG}{package}"))
         .join("&");
-    let url = format!("{URL}{arguments}");
+    let url = format!("{}/info?{arguments}", config::aur_rpc_url());
 
-    let response = reqwest::get(&url).await?.text().await?;
+    let response = CLIENT.get(&url).send().await?.text().await?;
     let aur_data: AurRPC = serde_json::de::from_str(&response)?;
     Ok(aur_data.results)
 }
@@ -144,3 +401,117 @@ pub enum Error {
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_with_persisted_cache() {
+        let path =
+            std::env::temp_dir().join(format!("archie-package-cache-{}", std::process::id()));
+        std::fs::write(&path, "glibc\npacman\n").unwrap();
+        let cache = read_package_cache(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let depends = HashSet::from(["glibc".to_string(), "paru".to_string()]);
+        assert_eq!(
+            aur_dependencies(depends.clone(), &cache, false),
+            HashSet::from(["paru".to_string()])
+        );
+        assert_eq!(aur_dependencies(depends.clone(), &cache, true), depends);
+    }
+
+    /// Answers with fixed dependencies instead of asking the AUR, remembering what was looked up.
+    struct MockAur {
+        dependencies: HashMap<Package, HashSet<Package>>,
+        lookups: std::sync::Mutex<Vec<Package>>,
+    }
+
+    impl AurClient for MockAur {
+        async fn package_status(
+            &self,
+            _packages: &HashSet<Package>,
+        ) -> Result<HashMap<Package, AurStatus>, Error> {
+            Ok(HashMap::new())
+        }
+
+        async fn dependencies(
+            &self,
+            packages: &HashSet<Package>,
+        ) -> Result<HashMap<Package, HashSet<Package>>, Error> {
+            self.lookups
+                .lock()
+                .unwrap()
+                .extend(packages.iter().cloned());
+            Ok(self
+                .dependencies
+                .iter()
+                .filter(|(package, _)| packages.contains(*package))
+                .map(|(package, dependencies)| (package.clone(), dependencies.clone()))
+                .collect())
+        }
+    }
+
+    fn names<const N: usize>(names: [&str; N]) -> HashSet<Package> {
+        names.into_iter().map(String::from).collect()
+    }
+
+    #[tokio::test]
+    async fn dependency_graph_looks_up_every_package_once() {
+        let aur = MockAur {
+            dependencies: HashMap::from([
+                ("yay".to_string(), names(["yay-dep", "paru", "missing"])),
+                ("yay-dep".to_string(), names(["yay"])),
+                ("pikaur".to_string(), names(["yay-dep"])),
+            ]),
+            lookups: Default::default(),
+        };
+
+        let graph = dependency_graph(&aur, &names(["yay", "pikaur"]), &names(["paru"]))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            graph.keys().cloned().collect::<HashSet<_>>(),
+            names(["yay", "yay-dep", "pikaur"])
+        );
+        let lookups = aur.lookups.into_inner().unwrap();
+        assert_eq!(lookups.len(), 4);
+        assert_eq!(
+            dependency_closure(&"pikaur".to_string(), &graph, &names(["paru"])),
+            names(["pikaur", "yay-dep", "yay"])
+        );
+    }
+
+    #[test]
+    fn package_without_depends_has_no_dependencies() {
+        let response = r#"{"results": [
+            {"Name": "meta", "LastModified": 1, "OutOfDate": null},
+            {"Name": "paru", "LastModified": 1, "OutOfDate": null, "Depends": ["git"]}
+        ]}"#;
+        let rpc: AurRPC = serde_json::from_str(response).unwrap();
+        let dependencies = dependency_map(rpc.results, &HashSet::new(), false);
+        assert_eq!(dependencies.get("meta"), Some(&HashSet::new()));
+        assert_eq!(
+            dependencies.get("paru"),
+            Some(&HashSet::from(["git".to_string()]))
+        );
+    }
+
+    #[test]
+    fn suggests_closest_packages() {
+        let candidates = ["paru", "paru-bin", "paru-git", "parui", "pacman-contrib"]
+            .map(String::from)
+            .to_vec();
+        assert_eq!(
+            closest_matches("paruu", candidates.clone()),
+            vec!["paru".to_string(), "parui".to_string()]
+        );
+        assert_eq!(
+            closest_matches("paru-bim", candidates),
+            vec!["paru-bin".to_string(), "paru-git".to_string()]
+        );
+        assert!(closest_matches("yay", vec!["paru".to_string()]).is_empty());
+    }
+}
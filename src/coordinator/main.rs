@@ -1,29 +1,36 @@
 mod aur;
 mod config;
 mod messages;
+mod notifier;
 mod orchestrator;
 mod repository;
 mod scheduler;
 mod state;
 mod stop_token;
+mod tls;
 mod web_server;
 
 use std::env::var;
+use crate::config::Role;
 use crate::messages::Message;
-use crate::stop_token::StopToken;
+use crate::stop_token::{shutdown_requested, StopToken};
 use coordinator::{abort_if_not_in_docker, combine_for_display, print_version};
 use signal_hook::consts::{SIGINT, SIGTERM};
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use thiserror::Error;
-use tokio::sync::broadcast::channel;
-use tokio::task::JoinSet;
-use tokio::time::sleep;
-use tracing::log::info;
+use tokio::sync::broadcast::{channel, Receiver, Sender};
+use tokio::task::{Id, JoinSet};
+use tokio::time::{sleep, timeout};
+use tracing::log::{error, info, warn};
 use tracing_subscriber::filter::FilterFn;
 use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*};
 
+/// The name of the orchestrator's task. The tasks receiving builds stop once it did.
+const ORCHESTRATOR_TASK: &str = "orchestrator";
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     abort_if_not_in_docker();
@@ -37,8 +44,12 @@ async fn main() -> Result<(), Error> {
 
     let mut set = JoinSet::new();
     let mut stop_token = StopToken::new();
-    let (send, receive) = channel::<Message>(128);
+    // Stops the web server and the repository after the orchestrator, so builds that finish
+    // during SHUTDOWN_BUILD_GRACE can still be uploaded.
+    let mut late_stop_token = StopToken::new();
+    let (send, receive) = channel::<Message>(config::channel_capacity());
     
+    check_channels().await;
     let pkg = state::tracked_packages().await;
     if pkg.is_empty() {
         info!("No packages being managed right now");
@@ -46,29 +57,114 @@ async fn main() -> Result<(), Error> {
         info!("Managing {}", combine_for_display(pkg));
     }
 
-    set.spawn(aur::update_non_aur_packages(stop_token.child()));
-    set.spawn(web_server::start(send.clone(), stop_token.child()));
-    set.spawn(orchestrator::start(
+    let mut tasks = HashMap::new();
+    let task = set.spawn(web_server::start(send.clone(), late_stop_token.child()));
+    tasks.insert(task.id(), "web server");
+    if config::role() == Role::Replica {
+        info!("Running as a replica. Only serving the repository");
+        let task = set.spawn(state::reload_periodically(stop_token.child()));
+        tasks.insert(task.id(), "state reloader");
+    } else {
+        spawn_primary_tasks(
+            &mut set,
+            &mut tasks,
+            &send,
+            receive,
+            &mut stop_token,
+            &mut late_stop_token,
+        );
+    }
+
+    setup_stop_mechanism(stop_token).await;
+
+    let mut late_stop_token = Some(late_stop_token);
+    // Running builds get their grace on top, so the tasks aren't abandoned while waiting for them.
+    let build_grace = Duration::from_secs(config::shutdown_build_grace().unsigned_abs());
+    let shutdown = timeout(config::shutdown_timeout() + build_grace, async {
+        loop {
+            if !tasks.values().any(|name| *name == ORCHESTRATOR_TASK) {
+                if let Some(late_stop_token) = late_stop_token.take() {
+                    late_stop_token.trigger_stop();
+                }
+            }
+            let Some(result) = set.join_next_with_id().await else {
+                break;
+            };
+            match result {
+                Ok((id, ())) => {
+                    tasks.remove(&id);
+                }
+                Err(err) => {
+                    error!("A task failed whilst shutting down: {err}");
+                    tasks.remove(&err.id());
+                }
+            }
+        }
+    })
+    .await;
+
+    if shutdown.is_err() {
+        warn!(
+            "Shutdown timed out. Exiting anyway, even though the {} did not stop",
+            combine_for_display(tasks.values())
+        );
+        std::process::exit(1);
+    }
+
+    info!("Exited gracefully");
+    Ok(())
+}
+
+/// Exits if packages are in a channel that is missing from `CHANNELS`, as their builds would
+/// not be served anymore.
+async fn check_channels() {
+    for (package, channel) in state::package_channels().await {
+        if config::channel(&channel).is_none() {
+            error!("{package} is in the channel '{channel}', which is missing from CHANNELS");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Spawns the tasks that build packages and maintain the repository. The receiver is dropped
+/// afterwards, so sending fails once all of them stopped. The repository stops with
+/// `late_stop_token`, as it has to add the builds that finish whilst the orchestrator stops.
+fn spawn_primary_tasks(
+    set: &mut JoinSet<()>,
+    tasks: &mut HashMap<Id, &str>,
+    send: &Sender<Message>,
+    receive: Receiver<Message>,
+    stop_token: &mut StopToken,
+    late_stop_token: &mut StopToken,
+) {
+    let task = set.spawn(aur::update_non_aur_packages(stop_token.child()));
+    tasks.insert(task.id(), "package cache");
+    let task = set.spawn(orchestrator::start(
         send.clone(),
         receive.resubscribe(),
         stop_token.child(),
     ));
-    set.spawn(repository::start(
+    tasks.insert(task.id(), ORCHESTRATOR_TASK);
+    let task = set.spawn(repository::start(
         send.clone(),
         receive.resubscribe(),
-        stop_token.child(),
+        late_stop_token.child(),
     ));
-    set.spawn(scheduler::start(
+    tasks.insert(task.id(), "repository");
+    let task = set.spawn(scheduler::start(
         send.clone(),
         receive.resubscribe(),
         stop_token.child(),
     ));
-    set.spawn(setup_stop_mechanism(stop_token));
-
-    set.join_all().await;
-
-    info!("Exited gracefully");
-    Ok(())
+    tasks.insert(task.id(), "scheduler");
+    if let Some(webhook) = config::notify_webhook() {
+        let task = set.spawn(notifier::start(
+            webhook,
+            receive.resubscribe(),
+            stop_token.child(),
+        ));
+        tasks.insert(task.id(), "notifier");
+    }
 }
 
 fn register_signals() -> Result<Arc<AtomicBool>, Error> {
@@ -85,7 +181,7 @@ async fn setup_stop_mechanism(stop_token: StopToken) {
         return;
     };
 
-    while !stop_triggered.load(Ordering::Relaxed) {
+    while !stop_triggered.load(Ordering::Relaxed) && !shutdown_requested() {
         sleep(Duration::from_millis(10)).await;
     }
 
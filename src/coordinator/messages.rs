@@ -1,18 +1,84 @@
+use crate::stop_token;
 use std::collections::HashSet;
+use thiserror::Error;
+use tokio::sync::broadcast::Sender;
+use tracing::error;
 
 pub type Package = String;
 
 #[derive(Clone)]
 pub enum Message {
-    AddPackages(HashSet<Package>),
-    AddDependencies(HashSet<Package>),
+    /// Packages got queued in the state through `state::queue_addition`
+    AddPackages,
     RemovePackages(HashSet<Package>),
-    BuildPackage(Package),
+    /// Removes the current builds of the packages from the repository, but keeps tracking them
+    PurgeArtifacts(HashSet<Package>),
+    BuildPackage {
+        package: Package,
+        priority: BuildPriority,
+    },
     BuildSuccess(Package),
     BuildFailure(Package),
-    ArtifactsUploaded {
+    /// Checks the AUR for updates right away, instead of waiting for the next scheduled check
+    CheckForUpdates,
+    /// Copies the build of a package from one channel into another
+    PromotePackage {
         package: Package,
-        files: Vec<String>,
-        build_time: i64,
+        from: String,
+        to: String,
     },
+    /// Artifacts got queued in the state through `state::queue_upload`
+    ArtifactsUploaded,
+    /// The failures of all packages got cleared, so their retries start over
+    FailuresReset,
+    /// Writing an upload ran out of space, so old builds should be evicted to honor `REPO_MAX_SIZE`
+    RepositoryFull,
+}
+
+/// How soon a build should start. Requested builds are started before scheduled ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BuildPriority {
+    /// Updates, VCS rebuilds and retries found by the scheduler
+    Scheduled,
+    /// Packages that got added or rebuilt by a user
+    Requested,
+}
+
+/// Sends the message to every task. The send only fails once all tasks stopped receiving, at
+/// which point nothing would act on the message anymore. Rather than dropping it silently, a
+/// shutdown of the coordinator is requested, so it gets restarted in a working state.
+pub fn send_message(sender: &Sender<Message>, message: Message) -> Result<(), SendFailed> {
+    sender.send(message).map(|_| ()).map_err(|err| {
+        error!("Failed to send message, as no task is receiving anymore. Shutting down: {err}");
+        stop_token::request_shutdown();
+        SendFailed
+    })
+}
+
+/// A message could not be sent, as no task is receiving anymore.
+#[derive(Debug, Error)]
+#[error("No task is receiving messages anymore")]
+pub struct SendFailed;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast::channel;
+
+    #[test]
+    fn failed_send_requests_shutdown() {
+        let (sender, receiver) = channel(1);
+        let message = Message::BuildPackage {
+            package: "paru".to_string(),
+            priority: BuildPriority::Requested,
+        };
+        assert!(send_message(&sender, message.clone()).is_ok());
+
+        drop(receiver);
+        let result = send_message(&sender, message);
+        let requested = stop_token::shutdown_requested();
+        stop_token::clear_shutdown_request();
+        assert!(result.is_err());
+        assert!(requested);
+    }
 }
@@ -0,0 +1,91 @@
+use crate::config::NotifyEvent;
+use crate::messages::{Message, Package};
+use crate::stop_token::StopToken;
+use crate::{config, state};
+use serde::Serialize;
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::select;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+use tracing::{debug, info, warn};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What gets posted to `NOTIFY_WEBHOOK`.
+#[derive(Serialize)]
+struct Notification {
+    event: &'static str,
+    package: Package,
+    /// Why the build failed, if the worker got to report it
+    reason: Option<String>,
+    time: i64,
+}
+
+/// Posts the configured build events to the webhook. Failing to do so only gets logged, so a
+/// broken webhook never holds up the builds.
+pub async fn start(webhook: String, mut receiver: Receiver<Message>, mut stop_token: StopToken) {
+    let events = config::notify_events();
+    let client = match reqwest::Client::builder().timeout(TIMEOUT).build() {
+        Ok(client) => client,
+        Err(err) => {
+            warn!("Failed to set up the webhook client, not sending notifications: {err}");
+            return;
+        }
+    };
+
+    loop {
+        let message = select! {
+            message = receiver.recv() => message,
+            () = stop_token.wait() => break,
+        };
+        let notification = match message {
+            Ok(Message::BuildFailure(package)) if events.contains(&NotifyEvent::BuildFailure) => {
+                let reason = state::package_info(&package)
+                    .await
+                    .and_then(|info| info.last_failure)
+                    .map(|failure| failure.reason.to_string());
+                notification("build_failure", package, reason)
+            }
+            Ok(Message::BuildSuccess(package)) if events.contains(&NotifyEvent::BuildSuccess) => {
+                notification("build_success", package, None)
+            }
+            Ok(_) => continue,
+            Err(RecvError::Lagged(lag)) => {
+                warn!("Missed {lag} messages, some notifications were not sent");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        match client.post(&webhook).json(&notification).send().await {
+            Ok(response) if response.status().is_success() => {
+                debug!(
+                    "Sent {} notification for {}",
+                    notification.event, notification.package
+                );
+            }
+            Ok(response) => warn!(
+                "The webhook answered the notification for {} with {}",
+                notification.package,
+                response.status()
+            ),
+            // The error would name the URL, which carries the token of the webhook.
+            Err(err) => warn!(
+                "Failed to send the notification for {}: {}",
+                notification.package,
+                err.without_url()
+            ),
+        }
+    }
+    info!("Stopped notifier");
+}
+
+fn notification(event: &'static str, package: Package, reason: Option<String>) -> Notification {
+    Notification {
+        event,
+        package,
+        reason,
+        time: OffsetDateTime::now_utc().unix_timestamp(),
+    }
+}
@@ -1,22 +1,71 @@
-use crate::config;
-use crate::messages::{Message, Package};
+use crate::{config, state};
+use crate::messages::{send_message, BuildPriority, Message, Package};
 use crate::stop_token::StopToken;
+use bollard::auth::DockerCredentials;
 use bollard::container::{
     Config, CreateContainerOptions, LogOutput, LogsOptions, StopContainerOptions,
+    UploadToContainerOptions,
 };
-use bollard::models::ContainerStateStatusEnum;
+use bollard::image::CreateImageOptions;
+use bollard::models::{ContainerStateStatusEnum, HostConfig};
 use bollard::Docker;
+use coordinator::PackageSource;
 use futures::future::join_all;
 use futures::StreamExt;
-use std::collections::HashMap;
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroUsize;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
 use thiserror::Error;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
 use tokio::sync::broadcast::{Receiver, Sender};
 use tokio::time::sleep;
 use tracing::{debug, info};
 use tracing::log::{error, warn};
 
+/// How often the connection to Docker gets checked.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Longest delay between two attempts to reach Docker.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// The volume the compiler cache is kept in, shared by all builds.
+const CCACHE_VOLUME: &str = "archie-ccache";
+const CCACHE_DIR: &str = "/home/worker/.cache/ccache";
+/// Where the sources of local packages are extracted to in the build container.
+const LOCAL_SOURCE_DIR: &str = "/home/worker/local";
+
+/// Whether Docker could be reached the last time the orchestrator tried.
+static DOCKER_AVAILABLE: AtomicBool = AtomicBool::new(true);
+
+pub fn docker_available() -> bool {
+    DOCKER_AVAILABLE.load(Relaxed)
+}
+
+/// Whether new builds have to wait for the `BUILD_WINDOW` to open.
+pub fn outside_build_window() -> bool {
+    config::build_window().is_some_and(|window| !window.contains(OffsetDateTime::now_utc()))
+}
+
+/// The packages that are being built right now.
+static ACTIVE_BUILDS: LazyLock<Mutex<HashSet<Package>>> =
+    LazyLock::new(|| Mutex::new(HashSet::new()));
+
+pub fn active_builds() -> HashSet<Package> {
+    ACTIVE_BUILDS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .clone()
+}
+
+fn publish_active_builds(active_containers: &HashMap<Package, String>) {
+    *ACTIVE_BUILDS.lock().unwrap_or_else(|err| err.into_inner()) =
+        active_containers.keys().cloned().collect();
+}
+
 pub async fn start(sender: Sender<Message>, receiver: Receiver<Message>, stop_token: StopToken) {
     if let Err(err) = run(sender, receiver, stop_token).await {
         error!("Orchestrator stopped with error: {err}");
@@ -32,23 +81,46 @@ async fn run(
 ) -> Result<(), Error> {
     let image = config::image();
     let docker = Docker::connect_with_socket_defaults()?;
+    wait_for_docker(&docker, &mut stop_token).await;
+    if stop_token.stopped() {
+        return Ok(());
+    }
+    if config::pull_image() {
+        if let Err(err) = pull_image(&docker, &image).await {
+            error!("Failed to pull {image}: {err}");
+        }
+    }
     if let Err(err) = docker.inspect_image(&image).await {
         return Err(Error::ImageNotAvailable(err));
     }
 
     let mut packages_to_build = Vec::new();
     let mut active_containers: HashMap<Package, String> = HashMap::new();
+    let mut throttled = false;
+    let mut waiting_for_window = false;
+    let mut last_health_check = Instant::now();
 
     loop {
+        if last_health_check.elapsed() >= HEALTH_CHECK_INTERVAL {
+            last_health_check = Instant::now();
+            if docker.ping().await.is_err() {
+                wait_for_docker(&docker, &mut stop_token).await;
+            }
+        }
         if stop_token.stopped() {
             let docker = Arc::new(docker);
+            // Docker sends SIGTERM first and only kills the containers once the grace is over.
+            let grace = config::shutdown_build_grace();
+            if grace > 0 && !active_containers.is_empty() {
+                info!("Giving the running builds {grace} seconds to finish");
+            }
             let stop_tasks: Vec<_> = active_containers
                 .into_iter()
                 .map(|(package, container)| {
                     let docker = docker.clone();
                     async move {
                         if let Err(err) = docker
-                            .stop_container(&container, Some(StopContainerOptions { t: 0 }))
+                            .stop_container(&container, Some(StopContainerOptions { t: grace }))
                             .await
                         {
                             error!("Failed to stop container {container} for {package}: {err}");
@@ -61,43 +133,261 @@ async fn run(
             join_all(stop_tasks).await;
             return Ok(());
         }
-        if !receiver.is_empty() {
-            let message = receiver.recv().await?;
-            if let Message::BuildPackage(package) = message {
-                packages_to_build.push(package);
-            } else if let Message::RemovePackages(packages) = message {
-                for package in packages {
-                    if let Some(index) = packages_to_build.iter().position(|to_build| **to_build == package) {
-                        packages_to_build.remove(index);
+        loop {
+            match receiver.try_recv() {
+                Ok(Message::BuildPackage { package, priority }) => {
+                    // A container with the package's name can only exist once.
+                    if !active_containers.contains_key(&package) {
+                        enqueue(&mut packages_to_build, package, priority);
                     }
-                    if let Some(container) = active_containers.remove(&package) {
-                        info!("Stopping build of package {package}, as it has been removed.");
-                        if let Err(err) = docker
-                            .stop_container(&container, Some(StopContainerOptions { t: 0 }))
-                            .await
-                        {
-                            error!("Failed to stop container {container} for {package}: {err}");
-                        };
-                        if let Err(err) = docker
-                            .remove_container(&container, None)
-                            .await
+                }
+                Ok(Message::RemovePackages(packages)) => {
+                    for package in packages {
+                        if let Some(index) = packages_to_build
+                            .iter()
+                            .position(|to_build| **to_build == package)
                         {
-                            error!("Failed to stop container {container} for {package}: {err}");
-                        };
+                            packages_to_build.remove(index);
+                        }
+                        if let Some(container) = active_containers.remove(&package) {
+                            info!("Stopping build of package {package}, as it has been removed.");
+                            if let Err(err) = docker
+                                .stop_container(&container, Some(StopContainerOptions { t: 0 }))
+                                .await
+                            {
+                                error!("Failed to stop container {container} for {package}: {err}");
+                            };
+                            if let Err(err) = docker.remove_container(&container, None).await {
+                                error!("Failed to stop container {container} for {package}: {err}");
+                            };
+                        }
+                        if !config::clean_build() {
+                            remove_build_volume(&docker, &package).await;
+                        }
                     }
                 }
+                Ok(_) => (),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Lagged(lag)) => {
+                    warn!("Missed {lag} messages. Rebuilding the build queue from the state");
+                    requeue_pending(&mut packages_to_build, &active_containers).await;
+                }
+                Err(TryRecvError::Closed) => return Err(RecvError::Closed.into()),
             }
         }
-        if !packages_to_build.is_empty() && active_containers.len() < config::max_builders() {
-            let package = packages_to_build.pop().unwrap();
-            let container_id = start_build_container(&docker, &image, &package).await?;
-            active_containers.insert(package, container_id);
+        let free_slots = config::max_builders().saturating_sub(active_containers.len());
+        let outside_window = outside_build_window();
+        if outside_window != waiting_for_window {
+            if outside_window {
+                info!("Outside of the build window, new builds wait until it opens");
+            } else {
+                info!("The build window opened, starting builds again");
+            }
+            waiting_for_window = outside_window;
+        }
+        // Whilst paused, builds stay queued until the coordinator is resumed.
+        if !packages_to_build.is_empty()
+            && free_slots > 0
+            && !outside_window
+            && !state::is_paused().await
+        {
+            if let Some(load) = host_load_too_high(active_containers.len()) {
+                if !throttled {
+                    info!("Not starting more builds whilst the host is under load ({load:.2})");
+                    throttled = true;
+                }
+            } else {
+                if throttled {
+                    info!("The host's load went down, starting builds again");
+                    throttled = false;
+                }
+                // One at a time with adaptive builders, so the load catches up before the next.
+                let slots = if config::adaptive_builders() {
+                    1
+                } else {
+                    free_slots
+                };
+                let pending: HashSet<Package> = packages_to_build
+                    .iter()
+                    .chain(active_containers.keys())
+                    .cloned()
+                    .collect();
+                let ready = state::ready_packages(&packages_to_build, &pending).await;
+                let mut to_start = take_ready_packages(
+                    &mut packages_to_build,
+                    &ready,
+                    slots,
+                    active_containers.is_empty(),
+                )
+                .into_iter();
+                while let Some(package) = to_start.next() {
+                    match start_build_container(&docker, &image, &package).await {
+                        Ok(container_id) => {
+                            state::clear_build_progress(&package).await;
+                            active_containers.insert(package, container_id);
+                        }
+                        Err(err) if docker.ping().await.is_err() => {
+                            warn!("Failed to start the build of {package}: {err}");
+                            packages_to_build.push(package);
+                            packages_to_build.extend(to_start.by_ref());
+                            wait_for_docker(&docker, &mut stop_token).await;
+                            break;
+                        }
+                        Err(err) => {
+                            error!("Failed to start the build of {package}: {err}");
+                            let _ = send_message(&sender, Message::BuildFailure(package));
+                        }
+                    }
+                }
+            }
         }
         clean_up_containers(&docker, &sender, &mut active_containers).await?;
+        publish_active_builds(&active_containers);
         sleep(Duration::from_millis(100)).await;
     }
 }
 
+/// The port the workers reach the coordinator at. Their address isn't covered by the certificate
+/// when serving HTTPS, so they get a plain HTTP listener of their own then.
+fn worker_facing_port() -> u16 {
+    if config::tls().is_some() {
+        config::worker_port()
+    } else {
+        config::port()
+    }
+}
+
+/// Pulls the image from its registry, logging the progress. Pulls `latest` if no tag is given.
+async fn pull_image(docker: &Docker, image: &str) -> Result<(), bollard::errors::Error> {
+    let name = image.rsplit('/').next().unwrap_or(image);
+    let tag = if name.contains([':', '@']) {
+        ""
+    } else {
+        "latest"
+    };
+    let options = CreateImageOptions {
+        from_image: image,
+        tag,
+        ..Default::default()
+    };
+    let credentials =
+        config::registry_credentials().map(|(username, password)| DockerCredentials {
+            username: Some(username),
+            password,
+            ..Default::default()
+        });
+
+    info!("Pulling {image}");
+    let mut progress = docker.create_image(Some(options), None, credentials);
+    while let Some(info) = progress.next().await {
+        let info = info?;
+        let status = info.status.unwrap_or_default();
+        match (info.id, info.progress) {
+            (Some(id), Some(progress)) => debug!("{id}: {status} {progress}"),
+            (Some(id), None) => info!("{id}: {status}"),
+            (None, _) => info!("{status}"),
+        }
+    }
+    info!("Pulled {image}");
+    Ok(())
+}
+
+/// Waits until Docker answers, retrying with a growing delay. Only returns early when the
+/// coordinator is stopping.
+async fn wait_for_docker(docker: &Docker, stop_token: &mut StopToken) {
+    let mut delay = Duration::from_secs(1);
+    loop {
+        match docker.ping().await {
+            Ok(_) => {
+                if !DOCKER_AVAILABLE.swap(true, Relaxed) {
+                    info!("Docker is reachable again");
+                }
+                return;
+            }
+            Err(err) => {
+                DOCKER_AVAILABLE.store(false, Relaxed);
+                warn!("Docker is unreachable, trying again in {delay:?}: {err}");
+            }
+        }
+
+        stop_token.sleep(delay).await;
+        if stop_token.stopped() {
+            return;
+        }
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// With `ADAPTIVE_BUILDERS`, another build only starts if there is an idle CPU. Returns the
+/// load if there isn't. One build is always allowed, so the queue can't stall.
+fn host_load_too_high(active_builds: usize) -> Option<f64> {
+    if !config::adaptive_builders() || active_builds == 0 {
+        return None;
+    }
+
+    let loadavg = std::fs::read_to_string("/proc/loadavg").ok()?;
+    let load: f64 = loadavg.split_whitespace().next()?.parse().ok()?;
+    let cpus = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    let idle = cpus as f64 - load;
+    (idle < 1.0).then_some(load)
+}
+
+/// Queues the package unless it is queued already. Packages are taken off the end of the queue,
+/// so requested builds are put there, moving them ahead if they were queued by the scheduler.
+/// Scheduled builds go to the front and wait for everything queued before them.
+fn enqueue(packages_to_build: &mut Vec<Package>, package: Package, priority: BuildPriority) {
+    match priority {
+        BuildPriority::Requested => {
+            packages_to_build.retain(|queued| *queued != package);
+            packages_to_build.push(package);
+        }
+        BuildPriority::Scheduled => {
+            if !packages_to_build.contains(&package) {
+                packages_to_build.insert(0, package);
+            }
+        }
+    }
+}
+
+/// Takes up to `slots` packages that are ready to be built off the queue, starting at its end.
+/// Should none be ready whilst nothing is being built, the queued packages depend on each other,
+/// so the next one is taken anyway.
+fn take_ready_packages(
+    packages_to_build: &mut Vec<Package>,
+    ready: &HashSet<Package>,
+    slots: usize,
+    idle: bool,
+) -> Vec<Package> {
+    let mut taken = Vec::new();
+    let mut index = packages_to_build.len();
+    while index > 0 && taken.len() < slots {
+        index -= 1;
+        if ready.contains(&packages_to_build[index]) {
+            taken.push(packages_to_build.remove(index));
+        }
+    }
+    if taken.is_empty() && idle && slots > 0 {
+        taken.extend(packages_to_build.pop());
+    }
+    taken
+}
+
+/// Drops queued packages that are no longer tracked and queues the ones that never got built,
+/// as their messages might have been among the missed ones.
+async fn requeue_pending(
+    packages_to_build: &mut Vec<Package>,
+    active_containers: &HashMap<Package, String>,
+) {
+    let tracked = state::tracked_packages().await;
+    let quarantined = state::quarantined_packages().await;
+    packages_to_build.retain(|package| tracked.contains(package));
+    for package in state::unbuilt_packages().await {
+        if !active_containers.contains_key(&package) && !quarantined.contains_key(&package) {
+            enqueue(packages_to_build, package, BuildPriority::Scheduled);
+        }
+    }
+}
+
 async fn start_build_container(
     docker: &Docker,
     image: &str,
@@ -107,10 +397,107 @@ async fn start_build_container(
         name: package.to_string(),
         ..Default::default()
     };
-    let env_var = format!("PACKAGE={package}");
+    let info = state::package_info(package).await;
+    let memory_limit = info
+        .as_ref()
+        .and_then(|info| info.memory_limit)
+        .unwrap_or_else(config::memory_limit);
+    let answers = info
+        .as_ref()
+        .map(|info| info.answers.clone())
+        .unwrap_or_default();
+    let pkgbuild_additions = info
+        .as_ref()
+        .map(|info| info.pkgbuild_additions.clone())
+        .unwrap_or_default();
+    let local_source = match &info {
+        Some(info) if info.source == PackageSource::Local => {
+            Some(state::read_local_source(package).await?)
+        }
+        _ => None,
+    };
+    let extra_build_args = match info {
+        Some(info) if !info.extra_build_args.is_empty() => info.extra_build_args,
+        _ => config::extra_build_args(),
+    };
+
+    let mut env = vec![
+        format!("PACKAGE={package}"),
+        format!("AUR_GIT_BASE={}", config::aur_git_base()),
+        format!("UPLOAD_RETRIES={}", config::upload_retries()),
+        format!("COORDINATOR_HOST={}", config::coordinator_host()),
+        format!("COORDINATOR_PORT={}", worker_facing_port()),
+    ];
+    if !extra_build_args.is_empty() {
+        env.push(format!("EXTRA_BUILD_ARGS={}", extra_build_args.join(" ")));
+    }
+    if !answers.is_empty() {
+        env.push(format!("BUILD_ANSWERS={}", answers.join("\n")));
+    }
+    if !pkgbuild_additions.is_empty() {
+        env.push(format!(
+            "PKGBUILD_ADDITIONS={}",
+            pkgbuild_additions.join("\n")
+        ));
+    }
+    if local_source.is_some() {
+        env.push("LOCAL_SOURCE=true".to_string());
+    }
+    if let Some(makeflags) = config::makeflags() {
+        env.push(format!("MAKEFLAGS={makeflags}"));
+    }
+    if let Some(exclude) = config::artifact_exclude() {
+        env.push(format!("ARTIFACT_EXCLUDE={exclude}"));
+    }
+    let mut binds = Vec::new();
+    if !config::clean_build() {
+        env.push("CLEAN_BUILD=false".to_string());
+        binds.push(format!("{}:/home/worker/build", build_volume(package)));
+    }
+    if config::ccache() {
+        env.extend([
+            "CCACHE=true".to_string(),
+            format!("CCACHE_DIR={CCACHE_DIR}"),
+        ]);
+        binds.push(format!("{CCACHE_VOLUME}:{CCACHE_DIR}"));
+    }
+    if let Some(proxy) = config::proxy() {
+        env.extend([
+            format!("HTTP_PROXY={proxy}"),
+            format!("HTTPS_PROXY={proxy}"),
+            // git doesn't pick up the variables above in every case, so set http.proxy as well
+            "GIT_CONFIG_COUNT=1".to_string(),
+            "GIT_CONFIG_KEY_0=http.proxy".to_string(),
+            format!("GIT_CONFIG_VALUE_0={proxy}"),
+        ]);
+        // The worker reaches the coordinator directly.
+        let host = config::coordinator_host();
+        match std::env::var("NO_PROXY") {
+            Ok(no_proxy) if !no_proxy.is_empty() => {
+                env.push(format!("NO_PROXY={no_proxy},{host}"));
+            }
+            _ => env.push(format!("NO_PROXY={host}")),
+        }
+    }
+    let labels: HashMap<String, String> = config::container_labels()
+        .into_iter()
+        .map(|(key, value)| (key, value.replace("{package}", package)))
+        .collect();
     let config = Config {
         image: Some(image),
-        env: Some(vec![&env_var]),
+        env: Some(env.iter().map(String::as_str).collect()),
+        labels: (!labels.is_empty()).then(|| {
+            labels
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect()
+        }),
+        host_config: Some(HostConfig {
+            memory: (memory_limit > 0).then_some(memory_limit),
+            binds: (!binds.is_empty()).then_some(binds),
+            network_mode: config::build_network(),
+            ..Default::default()
+        }),
         ..Default::default()
     };
 
@@ -123,10 +510,50 @@ async fn start_build_container(
         warn!("{x}");
     }
 
+    // Extracted by Docker into the container before it starts, the worker copies it from there.
+    if let Some(archive) = local_source {
+        let options = UploadToContainerOptions {
+            path: LOCAL_SOURCE_DIR,
+            ..Default::default()
+        };
+        if let Err(err) = docker
+            .upload_to_container(&response.id, Some(options), archive.into())
+            .await
+        {
+            remove_container(docker, &response.id).await;
+            return Err(err.into());
+        }
+    }
+
     docker.start_container::<String>(&response.id, None).await?;
     Ok(response.id)
 }
 
+/// The volume the build directory of a package is kept in between builds. Docker only allows a
+/// few characters in volume names, so the others are replaced.
+fn build_volume(package: &Package) -> String {
+    let name: String = package
+        .chars()
+        .map(|char| match char {
+            'a'..='z' | '0'..='9' | '.' | '_' | '-' => char,
+            _ => '_',
+        })
+        .collect();
+    format!("archie-build-{name}")
+}
+
+/// Removes the build directory of a package that is no longer tracked.
+async fn remove_build_volume(docker: &Docker, package: &Package) {
+    let volume = build_volume(package);
+    match docker.remove_volume(&volume, None).await {
+        Ok(()) => debug!("Removed volume {volume}"),
+        Err(bollard::errors::Error::DockerResponseServerError {
+            status_code: 404, ..
+        }) => (),
+        Err(err) => warn!("Failed to remove volume {volume}: {err}"),
+    }
+}
+
 async fn clean_up_containers(
     docker: &Docker,
     sender: &Sender<Message>,
@@ -136,6 +563,15 @@ async fn clean_up_containers(
     for (package, id) in active_containers.iter() {
         let container = match docker.inspect_container(id, None).await {
             Ok(container) => container,
+            // The container is gone, for example because Docker got restarted.
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => {
+                warn!("The container {id} of {package} disappeared");
+                let _ = send_message(sender, Message::BuildFailure(package.to_string()));
+                removed.push(package.to_owned());
+                continue;
+            }
             Err(err) => {
                 warn!("Failed to inspect container {id}: {err}");
                 continue;
@@ -158,11 +594,13 @@ async fn clean_up_containers(
         match status {
             ContainerStateStatusEnum::EXITED => {
                 if exit_code != 0 {
-                    warn!("{id} exited abnormally. Printing logs:");
-                    get_logs(docker, id).await;
-                    if let Err(err) = sender.send(Message::BuildFailure(package.to_string())) {
-                        error!("Failed to send message: {err}");
+                    // The worker reports failed commands itself, the logs are only needed if it
+                    // crashed before it could.
+                    if !failure_reported(package, state.started_at.as_deref()).await {
+                        warn!("{id} exited abnormally. Printing logs:");
+                        get_logs(docker, id).await;
                     }
+                    let _ = send_message(sender, Message::BuildFailure(package.to_string()));
                 }
                 remove_container(docker, id).await;
                 removed.push(package.to_owned());
@@ -181,12 +619,25 @@ async fn clean_up_containers(
     }
 
     for package in removed {
+        state::clear_build_progress(&package).await;
         active_containers.remove(&package);
     }
 
     Ok(())
 }
 
+/// Checks whether the worker sent a failure report after the container got started.
+async fn failure_reported(package: &Package, started_at: Option<&str>) -> bool {
+    let Some(started_at) = started_at.and_then(|time| OffsetDateTime::parse(time, &Rfc3339).ok())
+    else {
+        return false;
+    };
+    state::package_info(package)
+        .await
+        .and_then(|info| info.last_failure)
+        .is_some_and(|failure| failure.time >= started_at.unix_timestamp())
+}
+
 async fn remove_container(docker: &Docker, id: &str) {
     if let Err(err) = docker.remove_container(id, None).await {
         warn!("Failed to remove container {id}: {err}");
@@ -246,9 +697,78 @@ pub enum Error {
     #[error("Could not query image.")]
     ImageNotAvailable(bollard::errors::Error),
     #[error("Channel error {0}")]
-    Channel(#[from] tokio::sync::broadcast::error::RecvError),
+    Channel(#[from] RecvError),
     #[error("Failed to read environment variable: {0}")]
     Env(#[from] std::env::VarError),
     #[error("A join error occurred: {0}")]
     Join(#[from] tokio::task::JoinError),
+    #[error("Failed to read the local sources: {0}")]
+    LocalSource(#[from] state::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(packages: &[&str]) -> Vec<Package> {
+        packages.iter().map(|package| package.to_string()).collect()
+    }
+
+    #[test]
+    fn fills_all_free_slots_at_once() {
+        let mut packages_to_build = queue(&["paru", "yay", "pikaur"]);
+        let ready = packages_to_build.iter().cloned().collect();
+
+        let taken = take_ready_packages(&mut packages_to_build, &ready, 3, true);
+
+        assert_eq!(taken, queue(&["pikaur", "yay", "paru"]));
+        assert!(packages_to_build.is_empty());
+    }
+
+    #[test]
+    fn leaves_packages_that_are_not_ready() {
+        let mut packages_to_build = queue(&["paru", "rustup", "yay"]);
+        let ready = HashSet::from(["rustup".to_string(), "yay".to_string()]);
+
+        let taken = take_ready_packages(&mut packages_to_build, &ready, 3, false);
+
+        assert_eq!(taken, queue(&["yay", "rustup"]));
+        assert_eq!(packages_to_build, queue(&["paru"]));
+    }
+
+    #[test]
+    fn requested_builds_jump_ahead_of_scheduled_ones() {
+        let mut packages_to_build = Vec::new();
+        for package in ["paru", "yay", "pikaur"] {
+            enqueue(
+                &mut packages_to_build,
+                package.to_string(),
+                BuildPriority::Scheduled,
+            );
+        }
+        enqueue(
+            &mut packages_to_build,
+            "rustup".to_string(),
+            BuildPriority::Requested,
+        );
+        enqueue(
+            &mut packages_to_build,
+            "aurutils".to_string(),
+            BuildPriority::Scheduled,
+        );
+        // Requesting a build of a queued package moves it ahead as well.
+        enqueue(
+            &mut packages_to_build,
+            "yay".to_string(),
+            BuildPriority::Requested,
+        );
+        let ready = packages_to_build.iter().cloned().collect();
+
+        let taken = take_ready_packages(&mut packages_to_build, &ready, 5, true);
+
+        assert_eq!(
+            taken,
+            queue(&["yay", "rustup", "paru", "pikaur", "aurutils"])
+        );
+    }
 }
@@ -1,19 +1,32 @@
 use std::fs::exists;
-use crate::messages::{Message, Package};
+use crate::config::{Channel, DEFAULT_CHANNEL};
+use crate::messages::{send_message, Message, Package};
+use crate::state::PendingUpload;
 use crate::stop_token::StopToken;
 use crate::{config, state};
-use std::path::PathBuf;
+use coordinator::RepositoryStats;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::{LazyLock, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::fs::{remove_file, try_exists};
 use tokio::select;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::broadcast::{Receiver, Sender};
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 use tracing::log::info;
 
-pub const REPO_DIR: &str = "/output/";
 const REPO_ADD: &str = "repo-add";
 const REPO_REMOVE: &str = "repo-remove";
+const BSDTAR: &str = "bsdtar";
+/// How long the repository stats are reused before they are gathered again.
+const STATS_MAX_AGE: Duration = Duration::from_secs(60);
+
+/// The last repository stats and when they were gathered. Cleared whenever the repository changes.
+static STATS: LazyLock<Mutex<Option<(Instant, RepositoryStats)>>> =
+    LazyLock::new(|| Mutex::new(None));
 
 pub async fn start(sender: Sender<Message>, receive: Receiver<Message>, stop_token: StopToken) {
     if let Err(err) = run_repository(sender, receive, stop_token).await {
@@ -26,50 +39,86 @@ async fn run_repository(
     mut receive: Receiver<Message>,
     mut stop_token: StopToken,
 ) -> Result<(), Error> {
-    let repo_name = config::repo_name();
+    let channels = config::channels();
 
-    recreate_repo(&repo_name).await;
+    for channel in &channels {
+        std::fs::create_dir_all(channel_dir(channel))?;
+        sync_repo(channel).await;
+    }
+    // Uploads that were received before a restart.
+    add_pending_uploads(&sender).await;
 
     loop {
         let artifact = select! {
             work = receive.recv() => Some(work),
             () = stop_token.wait() => None,
         };
-        let Some(Ok(message)) = artifact else {
-            break;
+        let message = match artifact {
+            Some(Ok(message)) => message,
+            Some(Err(RecvError::Lagged(lag))) => {
+                // Missed removals would linger in the database, so rebuild it from the state.
+                warn!("Missed {lag} messages. Synchronizing the repository with the state");
+                for channel in &channels {
+                    sync_repo(channel).await;
+                }
+                add_pending_uploads(&sender).await;
+                continue;
+            }
+            Some(Err(RecvError::Closed)) | None => break,
         };
 
         match message {
-            Message::ArtifactsUploaded {
-                package,
-                files,
-                build_time,
-            } => {
-                info!("Successfully built {}", package);
-
-                if add_to_repo(&repo_name, &files) {
-                    state::build_package(&package, build_time, files).await;
-                    if let Err(err) = sender.send(Message::BuildSuccess(package.clone())) {
-                        error!("Failed to send message: {err}");
+            Message::ArtifactsUploaded => add_pending_uploads(&sender).await,
+            Message::RepositoryFull => prune().await,
+            Message::RemovePackages(packages) => {
+                let mut removals: HashMap<String, (Vec<String>, Vec<Package>)> = HashMap::new();
+                for package in packages {
+                    let Some(info) = state::package_info(&package).await else {
+                        continue;
+                    };
+                    let builds = info
+                        .build
+                        .map(|build| (info.channel, build))
+                        .into_iter()
+                        .chain(info.promoted);
+                    for (channel, mut build) in builds {
+                        if !build.files.is_empty() {
+                            let (files, packages) = removals.entry(channel).or_default();
+                            files.append(&mut build.files);
+                            packages.push(package.clone());
+                        }
+                    }
+                }
+                for channel in &channels {
+                    if let Some((files, packages)) = removals.get(&channel.name) {
+                        remove_from_repo(channel, files, packages);
                     }
                 }
             }
-            Message::RemovePackages(packages) => {
-                let mut files = Vec::new();
-                let mut packages_to_remove = Vec::new();
+            Message::PurgeArtifacts(packages) => {
                 for package in packages {
-                    let mut package_files = state::get_files(&package).await;
-                    if !package_files.is_empty() {
-                        files.append(&mut package_files);
-                        packages_to_remove.push(package);
+                    let channel = package_channel(&package).await;
+                    let files = state::get_files(&package).await;
+                    if !files.is_empty() {
+                        info!("Purging the artifacts of {package}");
+                        remove_from_repo(&channel, &files, std::slice::from_ref(&package));
                     }
+                    state::clear_build(&package).await;
                 }
-                remove_from_repo(&repo_name, &files, &packages_to_remove);
             }
-            Message::AddPackages(_)
-            | Message::AddDependencies(_)
-            | Message::BuildPackage(_)
+            Message::PromotePackage { package, from, to } => {
+                match (config::channel(&from), config::channel(&to)) {
+                    (Some(from), Some(to)) => promote(&package, &from, &to).await,
+                    _ => error!(
+                        "Can not promote {package} from {from} to {to}, as a channel is missing"
+                    ),
+                }
+            }
+            Message::AddPackages
+            | Message::BuildPackage { .. }
             | Message::BuildSuccess(_)
+            | Message::FailuresReset
+            | Message::CheckForUpdates
             | Message::BuildFailure { .. } => (),
         }
     }
@@ -78,61 +127,501 @@ async fn run_repository(
     Ok(())
 }
 
-async fn recreate_repo(repo_name: &str) {
-    debug!("Recreating repository");
+/// Adds the uploads queued in the state to the repository.
+async fn add_pending_uploads(sender: &Sender<Message>) {
+    for upload in state::take_pending_uploads().await {
+        add_upload(sender, upload).await;
+    }
+}
+
+async fn add_upload(sender: &Sender<Message>, upload: PendingUpload) {
+    let PendingUpload {
+        package,
+        files,
+        build_time,
+        commit,
+        checksums,
+    } = upload;
+    info!("Successfully built {}", package);
+    let channel = package_channel(&package).await;
+    let previous_files = state::get_files(&package).await;
+
+    if !make_room(&package, &channel, &files).await {
+        error!("Not adding {package} to the repository, as it would exceed REPO_MAX_SIZE");
+        remove_files(&channel, &files);
+        let _ = send_message(sender, Message::BuildFailure(package.clone()));
+        return;
+    }
+
+    if add_to_repo(&channel, &files) {
+        // repo-add deletes the files of the previous build, but not their checksums.
+        let replaced: Vec<String> = previous_files
+            .into_iter()
+            .filter(|file| !files.contains(file))
+            .collect();
+        remove_checksums(&channel, &replaced);
+        write_checksums(&channel, &checksums);
+        state::build_package(&package, build_time, files, commit, checksums).await;
+        let _ = send_message(sender, Message::BuildSuccess(package.clone()));
+    }
+}
+
+/// Copies the build of a package in one channel into another one and adds it to its database.
+async fn promote(package: &Package, from: &Channel, to: &Channel) {
+    let Some(build) = state::channel_build(package, &from.name)
+        .await
+        .filter(|build| !build.files.is_empty())
+    else {
+        warn!(
+            "Not promoting {package}, as it has no build in {}",
+            from.name
+        );
+        return;
+    };
+
+    let previous_files = state::channel_build(package, &to.name)
+        .await
+        .map(|build| build.files)
+        .unwrap_or_default();
+    let (source, target) = (channel_dir(from), channel_dir(to));
+    for file in &build.files {
+        if let Err(err) = tokio::fs::copy(source.join(file), target.join(file)).await {
+            error!(
+                "Failed to copy {file} from {} to {}: {err}",
+                from.name, to.name
+            );
+            return;
+        }
+    }
+
+    if add_to_repo(to, &build.files) {
+        // repo-add deletes the files of the previously promoted build it knows, but not their
+        // checksums, so whatever is left of the replaced build is removed here.
+        let (lingering, deleted): (Vec<String>, Vec<String>) = previous_files
+            .into_iter()
+            .filter(|file| !build.files.contains(file))
+            .partition(|file| target.join(file).exists());
+        remove_files(to, &lingering);
+        remove_checksums(to, &deleted);
+        write_checksums(to, &build.checksums);
+        info!("Promoted {package} from {} to {}", from.name, to.name);
+        state::set_promoted(package, &to.name, build).await;
+    }
+}
+
+/// The directory of a channel. The default channel lives at the root of the repository
+/// directory, so existing `pacman.conf` entries keep working.
+pub fn channel_dir(channel: &Channel) -> PathBuf {
+    let dir = config::repo_dir();
+    if channel.name == DEFAULT_CHANNEL {
+        dir
+    } else {
+        dir.join(&channel.name)
+    }
+}
+
+/// Looks up the channel the builds of a package go to.
+pub async fn package_channel(package: &Package) -> Channel {
+    config::channel(&state::package_channel(package).await).unwrap_or_else(config::default_channel)
+}
+
+fn db_name(channel: &Channel) -> String {
+    format!("{}.db.tar.zst", channel.repo_name)
+}
+
+/// Brings the database in line with the state by only adding and removing the packages that
+/// differ. Falls back to recreating it when the database is missing or can't be read.
+async fn sync_repo(channel: &Channel) {
+    let start = Instant::now();
+    let name = &channel.name;
+    let Some(db_files) = read_db_files(channel) else {
+        recreate_repo(channel).await;
+        info!(
+            "Recreated the repository of {name} in {:.2?}",
+            start.elapsed()
+        );
+        return;
+    };
+
+    let state_files: HashSet<String> = state::channel_files(name)
+        .await
+        .into_iter()
+        .filter(|file| package_name(file).is_some())
+        .collect();
+    let to_add: Vec<String> = state_files.difference(&db_files).cloned().collect();
+    let added: HashSet<&str> = to_add
+        .iter()
+        .filter_map(|file| package_name(file))
+        .collect();
+    // Packages that get a new file are replaced by repo-add, so they don't need to be removed.
+    let to_remove: Vec<Package> = db_files
+        .difference(&state_files)
+        .filter_map(|file| package_name(file))
+        .filter(|name| !added.contains(name))
+        .map(str::to_string)
+        .collect();
+
+    if to_add.is_empty() && to_remove.is_empty() {
+        info!(
+            "Repository of {name} matches the state, checked in {:.2?}",
+            start.elapsed()
+        );
+        return;
+    }
+    if !to_remove.is_empty() {
+        remove_from_repo(channel, &[], &to_remove);
+    }
+    if !to_add.is_empty() {
+        add_to_repo(channel, &to_add);
+    }
+    info!(
+        "Synchronized the repository of {name} in {:.2?}, added {} and removed {} packages",
+        start.elapsed(),
+        to_add.len(),
+        to_remove.len()
+    );
+}
+
+/// Reads the file names of all packages in the database.
+fn read_db_files(channel: &Channel) -> Option<HashSet<String>> {
+    let db = channel_dir(channel).join(db_name(channel));
+    if !exists(&db).unwrap_or(false) {
+        debug!("No database found at {}", db.display());
+        return None;
+    }
+
+    let output = match Command::new(BSDTAR)
+        .arg("-xOf")
+        .arg(&db)
+        .arg("*/desc")
+        .output()
+    {
+        Ok(output) => output,
+        Err(err) => {
+            error!("Failed to spawn {BSDTAR}: {err}");
+            return None;
+        }
+    };
+    if !output.status.success() {
+        warn!(
+            "Could not read the database: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return None;
+    }
+
+    let desc = String::from_utf8_lossy(&output.stdout);
+    let mut lines = desc.lines();
+    let mut files = HashSet::new();
+    while let Some(line) = lines.next() {
+        if line == "%FILENAME%" {
+            files.extend(lines.next().map(str::to_string));
+        }
+    }
+    Some(files)
+}
 
-    let repo_files = vec![
-        format!("{REPO_DIR}{repo_name}.db"),
-        format!("{REPO_DIR}{repo_name}.db.tar.zst"),
-        format!("{REPO_DIR}{repo_name}.files"),
-        format!("{REPO_DIR}{repo_name}.files.tar.zst"),
+/// Extracts the package name from a file named `<name>-<pkgver>-<pkgrel>-<arch>.pkg.tar.<ext>`.
+pub fn package_name(file: &str) -> Option<&str> {
+    let (stem, _) = file.split_once(".pkg.tar")?;
+    if file.ends_with(".sig") {
+        return None;
+    }
+    stem.rsplitn(4, '-').nth(3)
+}
+
+async fn recreate_repo(channel: &Channel) {
+    debug!("Recreating repository of {}", channel.name);
+
+    let repo_name = &channel.repo_name;
+    let repo_files = [
+        format!("{repo_name}.db"),
+        format!("{repo_name}.db.tar.zst"),
+        format!("{repo_name}.files"),
+        format!("{repo_name}.files.tar.zst"),
     ];
 
+    let dir = channel_dir(channel);
     for file in repo_files {
+        let file = dir.join(file);
         if try_exists(&file).await.ok().unwrap_or(false) {
             if let Err(err) = remove_file(&file).await {
-                error!("Failed to delete file {file}: {err}");
+                error!("Failed to delete file {}: {err}", file.display());
             }
         }
     }
 
-    let files = state::get_all_files().await;
+    let files = state::channel_files(&channel.name).await;
+
+    add_to_repo(channel, &files);
+}
+
+/// Evicts the packages with the oldest builds until the new files fit into `REPO_MAX_SIZE`.
+/// Nothing gets evicted if that can't free up enough space.
+async fn make_room(package: &Package, channel: &Channel, new_files: &[String]) -> bool {
+    let max_size = config::repo_max_size();
+    if max_size == 0 {
+        return true;
+    }
+
+    // The previous build of the package gets replaced, so its files don't count.
+    let replaced_files: Vec<String> = state::get_files(package)
+        .await
+        .into_iter()
+        .filter(|file| !new_files.contains(file))
+        .collect();
+    let size =
+        directory_size(&config::repo_dir()).saturating_sub(files_size(channel, &replaced_files));
+    evict_oldest(size, max_size, Some(package)).await
+}
 
-    add_to_repo(repo_name, &files);
+/// Evicts the packages with the oldest builds until the repository is below `REPO_MAX_SIZE`, so
+/// a retried upload that ran out of space might fit.
+async fn prune() {
+    let max_size = config::repo_max_size();
+    if max_size == 0 {
+        return;
+    }
+    let size = directory_size(&config::repo_dir());
+    if !evict_oldest(size, max_size, None).await {
+        warn!("The repository can not be pruned below REPO_MAX_SIZE");
+    }
 }
 
-fn add_to_repo(repo_name: &str, files: &Vec<String>) -> bool {
+/// Evicts the packages with the oldest builds, except `keep` and the packages others depend on,
+/// until `size` drops to `max_size`. Their promoted builds go along with them. Nothing gets
+/// evicted if that can't free up enough space.
+async fn evict_oldest(size: u64, max_size: u64, keep: Option<&Package>) -> bool {
+    if size <= max_size {
+        return true;
+    }
+
+    let required = state::required_packages().await;
+    let mut candidates = Vec::new();
+    for (candidate, build_time) in state::get_build_times(&state::tracked_packages().await).await {
+        if Some(&candidate) == keep || required.contains(&candidate) {
+            continue;
+        }
+        let builds = channel_builds(&candidate).await;
+        if builds.is_empty() {
+            continue;
+        }
+        let candidate_size = builds
+            .iter()
+            .map(|(channel, files)| files_size(channel, files))
+            .sum();
+        candidates.push(((candidate, builds), build_time, candidate_size));
+    }
+    let Some(evictions) = plan_evictions(size, max_size, candidates) else {
+        return false;
+    };
+
+    for (candidate, builds) in evictions {
+        warn!("Evicting {candidate} from the repository to stay below REPO_MAX_SIZE");
+        for (channel, files) in &builds {
+            remove_from_repo(channel, files, std::slice::from_ref(&candidate));
+        }
+        state::evict_build(&candidate).await;
+    }
+    true
+}
+
+/// The files of a package's build in its channel and of the builds promoted to other channels.
+async fn channel_builds(package: &Package) -> Vec<(Channel, Vec<String>)> {
+    let Some(info) = state::package_info(package).await else {
+        return Vec::new();
+    };
+    let mut builds = Vec::new();
+    if let Some(build) = info.build {
+        builds.push((package_channel(package).await, build.files));
+    }
+    builds.extend(
+        info.promoted
+            .into_iter()
+            .filter_map(|(channel, build)| Some((config::channel(&channel)?, build.files))),
+    );
+    builds.retain(|(_, files)| !files.is_empty());
+    builds
+}
+
+/// Picks candidates with their build time and size, oldest build first, until `size` fits into
+/// `max_size`. Returns `None` if evicting all of them still isn't enough.
+fn plan_evictions<T>(
+    mut size: u64,
+    max_size: u64,
+    mut candidates: Vec<(T, i64, u64)>,
+) -> Option<Vec<T>> {
+    candidates.sort_by_key(|(_, build_time, _)| *build_time);
+
+    let mut evictions = Vec::new();
+    for (candidate, _, candidate_size) in candidates {
+        if size <= max_size {
+            break;
+        }
+        size = size.saturating_sub(candidate_size);
+        evictions.push(candidate);
+    }
+    (size <= max_size).then_some(evictions)
+}
+
+fn directory_size(path: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .filter_map(Result::ok)
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => directory_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// Counts the package files in the directory and the directories below it.
+fn count_package_files(path: &Path) -> usize {
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .filter_map(Result::ok)
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => count_package_files(&entry.path()),
+            _ => usize::from(package_name(&entry.file_name().to_string_lossy()).is_some()),
+        })
+        .sum()
+}
+
+/// Asks `df` for the free space on the filesystem the directory is on.
+async fn free_space(path: &Path) -> Option<u64> {
+    let output = tokio::process::Command::new("df")
+        .args(["--output=avail", "-B1"])
+        .arg(path)
+        .output()
+        .await;
+    match output {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .nth(1)
+            .and_then(|line| line.trim().parse().ok()),
+        Ok(output) => {
+            warn!(
+                "df failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            None
+        }
+        Err(err) => {
+            warn!("Failed to spawn df: {err}");
+            None
+        }
+    }
+}
+
+/// The size and free space of the repository. They are gathered again once they are older than
+/// `STATS_MAX_AGE` or the repository changed, as that walks the whole repository.
+pub async fn stats() -> RepositoryStats {
+    let cached = cached_stats()
+        .as_ref()
+        .filter(|(gathered, _)| gathered.elapsed() < STATS_MAX_AGE)
+        .map(|(_, stats)| stats.clone());
+    if let Some(stats) = cached {
+        return stats;
+    }
+
+    let repo_dir = config::repo_dir();
+    let dir = repo_dir.clone();
+    let (size, package_files) =
+        tokio::task::spawn_blocking(move || (directory_size(&dir), count_package_files(&dir)))
+            .await
+            .unwrap_or_default();
+    let stats = RepositoryStats {
+        size,
+        package_files,
+        free_space: free_space(&repo_dir).await,
+    };
+    *cached_stats() = Some((Instant::now(), stats.clone()));
+    stats
+}
+
+fn cached_stats() -> MutexGuard<'static, Option<(Instant, RepositoryStats)>> {
+    STATS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The size of the files along with their checksum files.
+fn files_size(channel: &Channel, files: &[String]) -> u64 {
+    let repo_dir = channel_dir(channel);
+    files
+        .iter()
+        .flat_map(|file| [repo_dir.join(file), repo_dir.join(format!("{file}.sha256"))])
+        .filter_map(|path| std::fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+fn remove_files(channel: &Channel, files: &[String]) {
+    let repo_dir = channel_dir(channel);
+    for file in files {
+        if let Err(err) = std::fs::remove_file(repo_dir.join(file)) {
+            error!("Failed to delete {file}: {err}");
+        }
+    }
+    remove_checksums(channel, files);
+}
+
+/// Writes a `<file>.sha256` in the format of `sha256sum` next to every file, so downloads can be
+/// verified without the database.
+fn write_checksums(channel: &Channel, checksums: &HashMap<String, String>) {
+    let repo_dir = channel_dir(channel);
+    for (file, checksum) in checksums {
+        let path = repo_dir.join(format!("{file}.sha256"));
+        if let Err(err) = std::fs::write(path, format!("{checksum}  {file}\n")) {
+            error!("Failed to write the checksum of {file}: {err}");
+        }
+    }
+}
+
+fn remove_checksums(channel: &Channel, files: &[String]) {
+    let repo_dir = channel_dir(channel);
+    for file in files {
+        match std::fs::remove_file(repo_dir.join(format!("{file}.sha256"))) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                error!("Failed to delete the checksum of {file}: {err}");
+            }
+            _ => (),
+        }
+    }
+}
+
+fn add_to_repo(channel: &Channel, files: &[String]) -> bool {
     let mut command = Command::new(REPO_ADD);
-    command.current_dir(REPO_DIR);
+    command.current_dir(channel_dir(channel));
     command.args([
         "--new",
         "--remove",
         "--prevent-downgrade",
         "--verify",
-        &format!("{repo_name}.db.tar.zst"),
+        &db_name(channel),
     ]);
     command.args(files);
-    run_command(command)
+    let command_result = run_command(command);
+    *cached_stats() = None;
+    command_result
 }
 
-fn remove_from_repo(repo_name: &str, files: &Vec<String>, packages: &Vec<Package>) -> bool {
-    if !exists(PathBuf::new().join(REPO_DIR).join(format!("{repo_name}.db.tar.zst"))).unwrap_or(false) {
+fn remove_from_repo(channel: &Channel, files: &[String], packages: &[Package]) -> bool {
+    let dir = channel_dir(channel);
+    if !exists(dir.join(db_name(channel))).unwrap_or(false) {
         return false;
     }
 
     let mut command = Command::new(REPO_REMOVE);
-    command.current_dir(REPO_DIR);
-    command.args([&format!("{repo_name}.db.tar.zst")]);
+    command.current_dir(dir);
+    command.args([&db_name(channel)]);
     command.args(packages);
     let command_result = run_command(command);
-
-    let repo_dir = PathBuf::new().join(REPO_DIR);
-    for file in files {
-        if let Err(err) = std::fs::remove_file(repo_dir.join(file)) {
-            error!("Failed to delete {file}: {err}");
-        }
-    }
-
+    remove_files(channel, files);
+    *cached_stats() = None;
     command_result
 }
 
@@ -167,3 +656,30 @@ enum Error {
     #[error("Encountered an IO error")]
     IO(#[from] std::io::Error),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::plan_evictions;
+
+    #[test]
+    fn evicts_the_oldest_builds_first() {
+        let candidates = vec![("newest", 30, 40), ("oldest", 10, 40), ("middle", 20, 40)];
+
+        assert_eq!(
+            plan_evictions(150, 80, candidates),
+            Some(vec!["oldest", "middle"])
+        );
+    }
+
+    #[test]
+    fn evicts_nothing_if_there_is_enough_space() {
+        assert_eq!(plan_evictions(80, 80, vec![("old", 10, 40)]), Some(vec![]));
+    }
+
+    #[test]
+    fn refuses_when_eviction_cannot_free_enough_space() {
+        let candidates = vec![("old", 10, 20), ("older", 5, 20)];
+
+        assert_eq!(plan_evictions(150, 80, candidates), None);
+    }
+}
@@ -1,12 +1,13 @@
-use crate::aur::get_last_modified;
-use crate::messages::{Message, Package};
+use crate::aur::{AurClient, RpcClient};
+use crate::messages::{send_message, BuildPriority, Message, Package};
 use crate::scheduler::Error::CouldNotReachAUR;
-use crate::state::{get_build_times, tracked_packages};
+use crate::state::{get_build_times, tracked_packages, PendingAddition};
 use crate::stop_token::StopToken;
 use crate::{aur, config, state};
+use coordinator::PackageSource;
 use itertools::Itertools;
 use std::collections::{HashMap, HashSet};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use time::OffsetDateTime;
 use tokio::select;
 use tokio::sync::broadcast::error::RecvError;
@@ -15,6 +16,11 @@ use tracing::{debug, error, info, warn};
 
 const TIMEOUT: i64 = 4 * 60 * 60; // 4 Hours
 const RETRY_TIME: i64 = 5 * 60; // 5 minutes
+const MAX_BACKOFF: i64 = 60 * 60; // 1 Hour
+/// Duplicate build requests within this window are dropped.
+const DEBOUNCE_WINDOW: Duration = Duration::from_secs(60);
+/// Suffixes the AUR uses for packages built from the latest commit of a version control system.
+const VCS_SUFFIXES: [&str; 6] = ["-git", "-svn", "-hg", "-bzr", "-darcs", "-fossil"];
 
 pub async fn start(sender: Sender<Message>, receiver: Receiver<Message>, token: StopToken) {
     run(sender, receiver, token).await;
@@ -25,33 +31,88 @@ async fn run(sender: Sender<Message>, mut receiver: Receiver<Message>, mut token
     let stop_token = &mut token;
     let mut next_update_check = 0;
     let mut next_retry_check = 0;
+    let mut failed_checks: u32 = 0;
     let mut retries: HashMap<Package, u8> = HashMap::new();
+    // Builds requested within the `DEBOUNCE_WINDOW`.
+    let mut recent_requests: HashMap<Package, Instant> = HashMap::new();
+    // Builds found by the update check and when they are due.
+    let mut staggered: HashMap<Package, i64> = HashMap::new();
+    // Additions that were queued before a restart.
+    add_pending_packages(&sender, &mut recent_requests).await;
 
     loop {
         let now = OffsetDateTime::now_utc().unix_timestamp();
+        // Nothing gets requested whilst paused. Resuming asks for an update check.
+        let paused = state::is_paused().await;
 
-        if next_update_check < now {
-            if check_for_package_updates(&sender, stop_token).await.is_ok() {
-                next_update_check = now + TIMEOUT;
-                retries.clear();
-            } else {
-                next_update_check = now + RETRY_TIME;
+        if next_update_check < now && !paused {
+            match check_for_package_updates(&RpcClient).await {
+                Ok(builds) => {
+                    for (package, due) in stagger(builds, now, config::schedule_jitter()) {
+                        staggered.entry(package).or_insert(due);
+                    }
+                    next_update_check = now + TIMEOUT + jitter(config::schedule_jitter());
+                    failed_checks = 0;
+                    retries.clear();
+                }
+                Err(CouldNotReachAUR(err)) => {
+                    // Only the first failure is an error, so an AUR outage doesn't flood the logs.
+                    if failed_checks == 0 {
+                        error!("Failed to lookup package info in the AUR: {err}");
+                    } else {
+                        debug!("Failed to lookup package info in the AUR: {err}");
+                    }
+                    failed_checks += 1;
+                    let backoff = backoff(failed_checks);
+                    debug!(
+                        "AUR check failed {failed_checks} time(s) in a row. Retrying in {} minutes",
+                        backoff / 60
+                    );
+                    next_update_check = now + backoff;
+                }
             }
         }
 
-        if next_retry_check < now {
+        if !paused {
+            let due: Vec<Package> = staggered
+                .iter()
+                .filter(|(_, due)| **due <= now)
+                .map(|(package, _)| package.clone())
+                .collect();
+            for package in due {
+                staggered.remove(&package);
+                request_build(
+                    &sender,
+                    &mut recent_requests,
+                    package,
+                    BuildPriority::Scheduled,
+                );
+            }
+        }
+
+        if next_retry_check < now && !paused {
             for (package, attempt) in &retries {
                 if *attempt < config::max_retries() {
                     info!("Retrying build for {package}");
-                    send_message(&sender, Message::BuildPackage(package.clone()));
+                    request_build(
+                        &sender,
+                        &mut recent_requests,
+                        package.clone(),
+                        BuildPriority::Scheduled,
+                    );
                 }
             }
             next_retry_check = now + RETRY_TIME;
         }
 
+        let wait = staggered
+            .values()
+            .min()
+            .filter(|_| !paused)
+            .map_or(60, |due| (due - now).clamp(1, 60));
         let message: Option<Result<Message, RecvError>> = select! {
             message = receiver.recv() => Some(message),
-            () = stop_token.sleep(Duration::from_secs(60)) => None,
+            () = stop_token.sleep(Duration::from_secs(wait.unsigned_abs())) => None,
         };
         if stop_token.stopped() {
             break;
@@ -59,115 +120,483 @@ async fn run(sender: Sender<Message>, mut receiver: Receiver<Message>, mut token
 
         match message {
             Some(Ok(message)) => match message {
-                Message::AddPackages(packages) => {
-                    add_package(&sender, packages, false).await;
-                }
-                Message::AddDependencies(packages) => {
-                    add_package(&sender, packages, true).await;
-                }
+                Message::AddPackages => add_pending_packages(&sender, &mut recent_requests).await,
                 Message::RemovePackages(packages) => {
                     state::remove_packages(&packages).await;
                     info!("Stopped tracking {}", packages.iter().join(", "));
                     let unneeded = state::unneeded_dependencies().await;
                     if !unneeded.is_empty() {
-                        send_message(&sender, Message::RemovePackages(unneeded));
+                        let _ = send_message(&sender, Message::RemovePackages(unneeded));
                     }
                 }
                 Message::BuildSuccess(package) => {
                     retries.remove(&package);
                 }
                 Message::BuildFailure(package) => {
-                    if let Some(retries) = retries.get_mut(&package) {
-                        *retries += 1;
-                    } else {
-                        retries.insert(package.clone(), 1);
-                    };
+                    let failures = retries.entry(package.clone()).or_insert(0);
+                    *failures += 1;
+                    if *failures > config::max_retries() {
+                        warn!("{package} failed {failures} times in a row. Quarantining it until it gets updated or rebuilt manually");
+                        state::set_quarantined(&package, Some(now)).await;
+                        retries.remove(&package);
+                    }
                 }
-                Message::BuildPackage(_) | Message::ArtifactsUploaded { .. } => (),
+                Message::FailuresReset => retries.clear(),
+                Message::BuildPackage { package, .. } => {
+                    note_build_request(&mut recent_requests, package);
+                }
+                Message::CheckForUpdates => {
+                    info!("Checking for updates as requested");
+                    next_update_check = 0;
+                }
+                Message::PromotePackage { .. }
+                | Message::PurgeArtifacts(_)
+                | Message::ArtifactsUploaded
+                | Message::RepositoryFull => (),
             },
             Some(Err(RecvError::Closed)) => {
                 error!("Message channel closed");
                 break;
             }
             Some(Err(RecvError::Lagged(lag))) => {
-                error!("The message channel lagged by {lag}. This should not happen!");
+                // Missed additions are still queued in the state, and missed build requests get
+                // sent again by the update check.
+                warn!("Missed {lag} messages. Checking for updates right away");
+                add_pending_packages(&sender, &mut recent_requests).await;
+                next_update_check = 0;
             }
             _ => (),
         }
     }
 }
 
-async fn add_package(sender: &Sender<Message>, packages: HashSet<Package>, dependencies: bool) {
-    let aur_dependencies = match aur::get_dependencies(&packages).await {
-        Ok(deps) => deps,
-        Err(err) => {
-            error!(
-                "Failed to fetch dependencies for {packages:?}. Could not add them. Error: {err}"
-            );
-            return;
+/// Tracks the packages queued in the state.
+async fn add_pending_packages(
+    sender: &Sender<Message>,
+    recent_requests: &mut HashMap<Package, Instant>,
+) {
+    for addition in state::take_pending_additions().await {
+        let PendingAddition {
+            packages,
+            channel,
+            resolve_deps,
+            build_immediately,
+            resolved,
+        } = addition;
+        if resolve_deps {
+            add_package(
+                sender,
+                recent_requests,
+                packages,
+                &channel,
+                resolved,
+                build_immediately,
+            )
+            .await;
+        } else {
+            add_without_dependencies(
+                sender,
+                recent_requests,
+                packages,
+                &channel,
+                build_immediately,
+            )
+            .await;
+        }
+    }
+}
+
+/// Tracks the packages in the channel, along with the dependencies resolved when they were
+/// requested, unless those are tracked already. Without `build` nothing gets queued, so the
+/// packages wait for the next update check to build them as never built ones.
+async fn add_package(
+    sender: &Sender<Message>,
+    recent_requests: &mut HashMap<Package, Instant>,
+    packages: HashSet<Package>,
+    channel: &str,
+    mut resolved: HashMap<Package, HashSet<Package>>,
+    build: bool,
+) {
+    let packages = untracked_after_requesting(packages).await;
+    let tracked_packages = tracked_packages().await;
+    let closure: HashSet<Package> = packages
+        .iter()
+        .flat_map(|package| aur::dependency_closure(package, &resolved, &tracked_packages))
+        .collect();
+    for package in closure.into_iter().sorted() {
+        // Packages without dependencies have an empty entry, so a missing one wasn't found.
+        let Some(package_dependencies) = resolved.remove(&package) else {
+            error!("{package} is not in the AUR, so it can not be added");
+            continue;
+        };
+        let dependency = !packages.contains(&package);
+        state::track_package(
+            &package,
+            package_dependencies,
+            dependency,
+            channel,
+            PackageSource::Aur,
+        )
+        .await;
+        info!("Added new package {package} to {channel}");
+        if build {
+            request_build(sender, recent_requests, package, BuildPriority::Requested);
         }
-    };
+    }
+}
 
-    let mut dependency_copies = aur_dependencies.clone();
+/// Tracks the packages as having no dependencies, so they get built without asking the AUR.
+async fn add_without_dependencies(
+    sender: &Sender<Message>,
+    recent_requests: &mut HashMap<Package, Instant>,
+    packages: HashSet<Package>,
+    channel: &str,
+    build: bool,
+) {
+    let packages = untracked_after_requesting(packages).await;
     for package in packages {
         if !state::is_package_tracked(&package).await {
-            let Some(package_dependencies) = dependency_copies.remove(&package) else {
-                warn!("Failed to get dependencies for {package}. This might mean it is a meta package");
-                continue;
-            };
-            state::track_package(&package, package_dependencies, dependencies).await;
-            info!("Added new package {package}");
-            send_message(sender, Message::BuildPackage(package));
+            state::track_package(&package, HashSet::new(), false, channel, PackageSource::Aur)
+                .await;
+            info!("Added new package {package} to {channel} without resolving its dependencies");
+            if build {
+                request_build(sender, recent_requests, package, BuildPriority::Requested);
+            }
         }
     }
+}
 
-    let dependencies: HashSet<Package> = aur_dependencies.into_values().flatten().collect();
-    if !dependencies.is_empty() {
-        send_message(sender, Message::AddDependencies(dependencies));
+/// Picks the untracked packages, in alphabetical order, that can be tracked along with their
+/// dependencies in the graph without exceeding `MAX_TRACKED_PACKAGES`. Only checked when the
+/// packages are requested, so the additions queued for the scheduler are within the limit.
+pub fn packages_within_limit(
+    packages: &HashSet<Package>,
+    graph: &HashMap<Package, HashSet<Package>>,
+    tracked_packages: &HashSet<Package>,
+    max: usize,
+) -> HashSet<Package> {
+    let mut accepted = HashSet::new();
+    let mut known = tracked_packages.clone();
+    for package in packages.iter().sorted() {
+        let closure = aur::dependency_closure(package, graph, &known);
+        if known.len() + closure.len() > max {
+            continue;
+        }
+        known.extend(closure);
+        accepted.insert(package.clone());
     }
+    accepted
 }
 
-async fn check_for_package_updates(
-    sender: &Sender<Message>,
-    stop_token: &mut StopToken,
-) -> Result<(), Error> {
+/// Marks the packages that are tracked already as requested by the user, so a dependency the user
+/// added explicitly isn't removed along with the packages depending on it. Returns the others.
+async fn untracked_after_requesting(packages: HashSet<Package>) -> HashSet<Package> {
+    let tracked_packages = tracked_packages().await;
+    let (tracked, untracked): (HashSet<Package>, HashSet<Package>) = packages
+        .into_iter()
+        .partition(|package| tracked_packages.contains(package));
+    for package in state::mark_as_requested(&tracked).await {
+        info!("Keeping {package}, which was tracked as a dependency, as an added package");
+    }
+    untracked
+}
+
+/// Returns the packages that need to be built.
+async fn check_for_package_updates<A: AurClient>(aur: &A) -> Result<Vec<Package>, Error> {
     debug!("Checking for package updates");
     let tracked_packages = tracked_packages().await;
     let mut never_built = tracked_packages.clone();
+    let build_times = get_build_times(&tracked_packages).await;
+    // Local packages only get rebuilt when their sources are uploaded again.
+    let local_packages = state::local_packages().await;
+    let aur_packages: HashSet<Package> = tracked_packages
+        .difference(&local_packages)
+        .cloned()
+        .collect();
 
-    let last_modified = match get_last_modified(&tracked_packages).await {
-        Ok(last_modified) => last_modified,
-        Err(err) => {
-            error!("Failed to lookup package info in the AUR: {err}");
-            stop_token.sleep(Duration::from_secs(5 * 60)).await;
-            return Err(CouldNotReachAUR);
-        }
-    };
+    let Updates {
+        last_modified,
+        outdated,
+        out_of_date,
+    } = find_updates(aur, &aur_packages, &build_times)
+        .await
+        .map_err(CouldNotReachAUR)?;
 
-    for (package, build_time) in get_build_times(&tracked_packages).await {
-        if let Some(last_modified) = last_modified.get(&package) {
-            if *last_modified > build_time {
-                info!("{package} needs to be rebuilt");
-                send_message(sender, Message::BuildPackage(package.to_string()));
-            }
+    if config::check_out_of_date() {
+        update_out_of_date_flags(&aur_packages, &out_of_date).await;
+    }
+
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let mut builds = Vec::new();
+    let mut quarantined = HashSet::new();
+    for (package, since) in state::quarantined_packages().await {
+        if last_modified
+            .get(&package)
+            .is_some_and(|last_modified| *last_modified > since)
+        {
+            info!("{package} got updated since it was quarantined. Releasing it from quarantine");
+            state::set_quarantined(&package, None).await;
+        } else {
+            never_built.remove(&package);
+            quarantined.insert(package);
         }
+    }
+
+    for (package, build_time) in build_times {
         never_built.remove(&package);
+        if quarantined.contains(&package) {
+            continue;
+        }
+        if outdated.contains(&package) {
+            info!("{package} needs to be rebuilt");
+            builds.push(package);
+        } else if !local_packages.contains(&package)
+            && is_vcs_rebuild_due(&package, build_time, now).await
+        {
+            info!("{package} is a VCS package and is due for its scheduled rebuild");
+            state::set_last_vcs_rebuild(&package, now).await;
+            builds.push(package);
+        }
     }
 
     for package in never_built {
         info!("{package} needs to be built");
-        send_message(sender, Message::BuildPackage(package));
+        builds.push(package);
     }
 
-    Ok(())
+    Ok(builds)
+}
+
+struct Updates {
+    last_modified: HashMap<Package, i64>,
+    /// Built packages that changed in the AUR since their build
+    outdated: HashSet<Package>,
+    /// Packages flagged as out of date in the AUR and when they got flagged
+    out_of_date: HashMap<Package, i64>,
+}
+
+async fn find_updates<A: AurClient>(
+    aur: &A,
+    tracked_packages: &HashSet<Package>,
+    build_times: &HashMap<Package, i64>,
+) -> Result<Updates, aur::Error> {
+    let status = aur.package_status(tracked_packages).await?;
+    let last_modified: HashMap<Package, i64> = status
+        .iter()
+        .map(|(package, status)| (package.clone(), status.last_modified))
+        .collect();
+    let out_of_date = status
+        .into_iter()
+        .filter_map(|(package, status)| status.out_of_date.map(|flagged| (package, flagged)))
+        .collect();
+    let outdated = build_times
+        .iter()
+        .filter(|(package, build_time)| {
+            last_modified
+                .get(*package)
+                .is_some_and(|last_modified| last_modified > build_time)
+        })
+        .map(|(package, _)| package.clone())
+        .collect();
+
+    Ok(Updates {
+        last_modified,
+        outdated,
+        out_of_date,
+    })
 }
 
-fn send_message(sender: &Sender<Message>, message: Message) {
-    if let Err(err) = sender.send(message) {
-        error!("There was an error send a message: {err}");
+/// Remembers which packages got flagged as out of date, so users learn about pending updates
+/// before the maintainer pushes them. Rebuilding wouldn't help, so they only get reported.
+async fn update_out_of_date_flags(
+    tracked_packages: &HashSet<Package>,
+    out_of_date: &HashMap<Package, i64>,
+) {
+    let previously_flagged = state::out_of_date_packages().await;
+    for package in tracked_packages {
+        let flagged = out_of_date.get(package).copied();
+        if flagged == previously_flagged.get(package).copied() {
+            continue;
+        }
+        if flagged.is_some() {
+            warn!("{package} got flagged as out of date in the AUR. Its maintainer should push an update soon");
+        } else {
+            info!("{package} is no longer flagged as out of date");
+        }
+        state::set_out_of_date(package, flagged).await;
     }
 }
 
+/// VCS packages don't update their `LastModified` when upstream moves, so they get rebuilt
+/// every `VCS_REBUILD_INTERVAL` instead.
+async fn is_vcs_rebuild_due(package: &Package, build_time: i64, now: i64) -> bool {
+    let interval = config::vcs_rebuild_interval();
+    if interval <= 0 || !VCS_SUFFIXES.iter().any(|suffix| package.ends_with(suffix)) {
+        return false;
+    }
+
+    let last_rebuild = state::last_vcs_rebuild(package)
+        .await
+        .map_or(build_time, |time| time.max(build_time));
+    now - last_rebuild >= interval
+}
+
+/// Doubles the wait after every consecutive failure, starting at `RETRY_TIME` and capped at
+/// `MAX_BACKOFF`.
+fn backoff(failed_checks: u32) -> i64 {
+    let exponent = failed_checks.saturating_sub(1).min(16);
+    RETRY_TIME.saturating_mul(1 << exponent).min(MAX_BACKOFF)
+}
+
+/// Asks for a build, unless one was already requested within the `DEBOUNCE_WINDOW`.
+fn request_build(
+    sender: &Sender<Message>,
+    recent_requests: &mut HashMap<Package, Instant>,
+    package: Package,
+    priority: BuildPriority,
+) {
+    let now = Instant::now();
+    recent_requests.retain(|_, requested| now.duration_since(*requested) < DEBOUNCE_WINDOW);
+    if recent_requests.contains_key(&package) {
+        debug!("Not building {package} again, as it was requested moments ago");
+        return;
+    }
+    recent_requests.insert(package.clone(), now);
+    let _ = send_message(sender, Message::BuildPackage { package, priority });
+}
+
+/// A random delay of up to `max` seconds.
+fn jitter(max: i64) -> i64 {
+    if max <= 0 {
+        return 0;
+    }
+    fastrand::i64(0..=max)
+}
+
+/// Spreads the builds evenly over the next `window` seconds in a random order, so the builders
+/// aren't handed all of them at once. Returns when each of them is due.
+fn stagger(mut builds: Vec<Package>, now: i64, window: i64) -> Vec<(Package, i64)> {
+    fastrand::shuffle(&mut builds);
+    let count = i64::try_from(builds.len()).unwrap_or(i64::MAX);
+    let step = if window > 0 { window / count.max(1) } else { 0 };
+    builds
+        .into_iter()
+        .zip(0..)
+        .map(|(package, index)| (package, now + index * step))
+        .collect()
+}
+
+/// Remembers builds requested by someone else, like a manual rebuild.
+fn note_build_request(recent_requests: &mut HashMap<Package, Instant>, package: Package) {
+    recent_requests.entry(package).or_insert_with(Instant::now);
+}
+
 enum Error {
-    CouldNotReachAUR,
+    CouldNotReachAUR(aur::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aur::AurStatus;
+
+    /// Answers with fixed modification times instead of asking the AUR.
+    struct MockAur {
+        last_modified: HashMap<Package, i64>,
+    }
+
+    impl AurClient for MockAur {
+        async fn package_status(
+            &self,
+            packages: &HashSet<Package>,
+        ) -> Result<HashMap<Package, AurStatus>, aur::Error> {
+            Ok(self
+                .last_modified
+                .iter()
+                .filter(|(package, _)| packages.contains(*package))
+                .map(|(package, time)| {
+                    let status = AurStatus {
+                        last_modified: *time,
+                        out_of_date: None,
+                    };
+                    (package.clone(), status)
+                })
+                .collect())
+        }
+
+        async fn dependencies(
+            &self,
+            _packages: &HashSet<Package>,
+        ) -> Result<HashMap<Package, HashSet<Package>>, aur::Error> {
+            Ok(HashMap::new())
+        }
+    }
+
+    fn names<const N: usize>(names: [&str; N]) -> HashSet<Package> {
+        names.into_iter().map(String::from).collect()
+    }
+
+    fn packages<const N: usize>(entries: [(&str, i64); N]) -> HashMap<Package, i64> {
+        entries
+            .into_iter()
+            .map(|(package, time)| (package.to_string(), time))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn package_modified_after_its_build_needs_rebuild() {
+        let aur = MockAur {
+            last_modified: packages([("updated", 200), ("current", 50), ("unbuilt", 10)]),
+        };
+        let build_times = packages([("updated", 100), ("current", 100)]);
+        let tracked = ["updated", "current", "unbuilt"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        let updates = find_updates(&aur, &tracked, &build_times).await.unwrap();
+
+        assert_eq!(updates.outdated, HashSet::from(["updated".to_string()]));
+        assert_eq!(updates.last_modified.len(), 3);
+    }
+
+    #[test]
+    fn builds_are_spread_over_the_window() {
+        let builds: Vec<Package> = (0..5).map(|index| format!("package-{index}")).collect();
+
+        let due: Vec<i64> = stagger(builds.clone(), 1000, 300)
+            .into_iter()
+            .map(|(_, due)| due)
+            .sorted()
+            .collect();
+
+        assert_eq!(due, vec![1000, 1060, 1120, 1180, 1240]);
+        assert!(stagger(builds, 1000, 0)
+            .into_iter()
+            .all(|(_, due)| due == 1000));
+    }
+
+    #[test]
+    fn packages_beyond_the_limit_are_rejected() {
+        let packages = names(["yay", "pikaur", "aurutils"]);
+
+        let accepted = packages_within_limit(&packages, &HashMap::new(), &names(["paru"]), 3);
+
+        assert_eq!(accepted, names(["aurutils", "pikaur"]));
+    }
+
+    #[test]
+    fn dependencies_count_towards_the_limit() {
+        let graph = HashMap::from([
+            ("yay".to_string(), names(["yay-dep"])),
+            ("yay-dep".to_string(), names(["yay-dep-dep", "paru"])),
+            ("yay-dep-dep".to_string(), HashSet::new()),
+            ("pikaur".to_string(), HashSet::new()),
+        ]);
+
+        let accepted =
+            packages_within_limit(&names(["yay", "pikaur"]), &graph, &names(["paru"]), 3);
+
+        assert_eq!(accepted, names(["pikaur"]));
+    }
 }
@@ -1,14 +1,26 @@
+use crate::config::{self, DEFAULT_CHANNEL};
 use crate::messages::Package;
+use crate::stop_token::StopToken;
+use coordinator::{BuildProgress, BuildStage, FailureReason, OverridesUpdate, PackageSource};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::fs::{exists, read_to_string};
+use std::path::PathBuf;
 use std::sync::{Arc, LazyLock};
+use std::time::Duration;
 use thiserror::Error;
-use tokio::fs::write;
-use tokio::sync::RwLock;
-use tracing::error;
+use tokio::fs::{rename, write};
+use tokio::sync::{Mutex, RwLock};
+use tracing::{debug, error, info, warn};
 
-const STATE_FILE: &str = "/config/state.json";
+/// Names of the state file and the file it is written to first, within `STATE_DIR`.
+const STATE_FILE: &str = "state.json";
+const STATE_FILE_TEMP: &str = "state.json.tmp";
+/// The directory within `STATE_DIR` the uploaded sources of local packages are kept in.
+const LOCAL_SOURCES_DIR: &str = "local";
+
+/// How often a replica reads the state written by the primary.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
 static STATE: LazyLock<State> = LazyLock::new(|| match load_state() {
     Ok(state) => state,
     Err(err) => {
@@ -16,83 +28,527 @@ static STATE: LazyLock<State> = LazyLock::new(|| match load_state() {
         std::process::exit(2);
     }
 });
+/// Held whilst the state file is written, so concurrent saves don't share the temporary file.
+static SAVE_LOCK: Mutex<()> = Mutex::const_new(());
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct PackageInfo {
     pub is_dependency: bool,
     pub dependencies: HashSet<Package>,
     pub build: Option<Build>,
+    #[serde(default)]
+    pub last_vcs_rebuild: Option<i64>,
+    #[serde(default)]
+    pub memory_limit: Option<i64>,
+    #[serde(default)]
+    pub extra_build_args: Vec<String>,
+    /// Lines written to the input of the build
+    #[serde(default)]
+    pub answers: Vec<String>,
+    /// Lines appended to the PKGBUILD
+    #[serde(default)]
+    pub pkgbuild_additions: Vec<String>,
+    #[serde(default)]
+    pub last_failure: Option<Failure>,
+    /// When the package got quarantined for failing all its retries
+    #[serde(default)]
+    pub quarantined_since: Option<i64>,
+    /// When the package got flagged as out of date in the AUR
+    #[serde(default)]
+    pub out_of_date: Option<i64>,
+    /// The channel the package's builds are added to
+    #[serde(default = "default_channel")]
+    pub channel: String,
+    /// Builds that got promoted to other channels, by channel
+    #[serde(default)]
+    pub promoted: HashMap<String, Build>,
+    #[serde(default)]
+    pub source: PackageSource,
+}
+
+fn default_channel() -> String {
+    DEFAULT_CHANNEL.to_string()
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Build {
     pub time: i64,
     pub files: Vec<String>,
+    /// The commit of the PKGBUILD repository the package was built from
+    #[serde(default)]
+    pub commit: Option<String>,
+    /// The SHA-256 of every file, by file name
+    #[serde(default)]
+    pub checksums: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Failure {
+    pub time: i64,
+    pub command: String,
+    pub exit_code: Option<i32>,
+    pub output: Vec<String>,
+    #[serde(default)]
+    pub reason: FailureReason,
+}
+
+/// Packages waiting to be tracked by the scheduler.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PendingAddition {
+    pub packages: HashSet<Package>,
+    pub channel: String,
+    /// Tracks the packages without dependencies if not set, instead of asking the AUR
+    pub resolve_deps: bool,
+    /// Only tracks the packages if not set, leaving their first build to the scheduler
+    pub build_immediately: bool,
+    /// The packages and their untracked AUR dependencies, each with its own dependencies, as
+    /// resolved when they got added. The dependencies get tracked along with the packages.
+    #[serde(default)]
+    pub resolved: HashMap<Package, HashSet<Package>>,
+}
+
+/// Artifacts that are in the repository directory, but not added to its database yet.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PendingUpload {
+    pub package: Package,
+    pub files: Vec<String>,
+    pub build_time: i64,
+    pub commit: Option<String>,
+    pub checksums: HashMap<String, String>,
 }
 
 #[derive(Serialize, Deserialize)]
 struct Persistent {
     pub package_status: HashMap<Package, PackageInfo>,
+    /// Work is queued here before the tasks doing it are notified, so it isn't lost if they miss
+    /// the message
+    #[serde(default)]
+    pub pending_additions: Vec<PendingAddition>,
+    #[serde(default)]
+    pub pending_uploads: Vec<PendingUpload>,
+}
+
+/// State that only matters whilst the coordinator is running, so it isn't saved.
+#[derive(Default)]
+struct Ephemeral {
+    pub build_progress: HashMap<Package, BuildProgress>,
+    /// Holds back new builds whilst set
+    pub paused: bool,
 }
 
 #[derive(Clone)]
 struct State {
     persistent: Arc<RwLock<Persistent>>,
+    ephemeral: Arc<RwLock<Ephemeral>>,
+}
+
+/// The shared handle to the state. Its contents can be replaced through [`reload`].
+fn state() -> &'static State {
+    &STATE
 }
 
 fn load_state() -> Result<State, Error> {
-    let persistent = if exists(STATE_FILE)? {
-        serde_json::de::from_str(&read_to_string(STATE_FILE)?)?
+    Ok(State {
+        persistent: Arc::new(RwLock::new(read_persistent()?)),
+        ephemeral: Arc::new(RwLock::new(Ephemeral::default())),
+    })
+}
+
+fn read_persistent() -> Result<Persistent, Error> {
+    let state_file = config::state_dir().join(STATE_FILE);
+    Ok(if exists(&state_file)? {
+        serde_json::de::from_str(&read_to_string(&state_file)?)?
     } else {
         Persistent {
             package_status: HashMap::new(),
+            pending_additions: Vec::new(),
+            pending_uploads: Vec::new(),
         }
-    };
-
-    Ok(State {
-        persistent: Arc::new(RwLock::new(persistent)),
     })
 }
 
+/// Reads the state file again, replacing the state everyone shares.
+pub async fn reload() -> Result<(), Error> {
+    let persistent = read_persistent()?;
+    *state().persistent.write().await = persistent;
+    Ok(())
+}
+
+/// Keeps re-reading the state file, so a replica picks up the changes made by the primary.
+pub async fn reload_periodically(mut stop_token: StopToken) {
+    loop {
+        stop_token.sleep(RELOAD_INTERVAL).await;
+        if stop_token.stopped() {
+            break;
+        }
+
+        match reload().await {
+            Ok(()) => debug!("Reloaded the state"),
+            Err(err) => warn!("Failed to reload the state, keeping the previous one: {err}"),
+        }
+    }
+    info!("Stopped reloading the state");
+}
+
 async fn save_state() {
-    let state = STATE.persistent.read().await;
+    let _saving = SAVE_LOCK.lock().await;
+    let state = state().persistent.read().await;
     let Ok(serialized) = serde_json::ser::to_vec(&*state) else {
         error!("Failed to serialize state file.");
         return;
     };
-    if let Err(err) = write(STATE_FILE, serialized).await {
+    drop(state);
+    // Replace the file in one go, so a replica never reads a partially written state.
+    let state_dir = config::state_dir();
+    let (state_file, state_file_temp) =
+        (state_dir.join(STATE_FILE), state_dir.join(STATE_FILE_TEMP));
+    if let Err(err) = write(&state_file_temp, serialized).await {
         error!("Encountered an error whilst writing state file: {err}");
+        return;
+    }
+    if let Err(err) = rename(&state_file_temp, &state_file).await {
+        error!("Encountered an error whilst replacing state file: {err}");
     }
 }
 
-pub async fn build_package(package: &Package, build_time: i64, files: Vec<String>) {
-    let mut state = STATE.persistent.write().await;
+/// Queues packages for the scheduler to track. It has to be notified through
+/// [`Message::AddPackages`](crate::messages::Message::AddPackages) afterwards.
+pub async fn queue_addition(addition: PendingAddition) {
+    state()
+        .persistent
+        .write()
+        .await
+        .pending_additions
+        .push(addition);
+    save_state().await;
+}
+
+/// Removes the queued additions from the state, in the order they were queued.
+pub async fn take_pending_additions() -> Vec<PendingAddition> {
+    let additions = std::mem::take(&mut state().persistent.write().await.pending_additions);
+    if !additions.is_empty() {
+        save_state().await;
+    }
+    additions
+}
+
+/// Queues uploaded artifacts for the repository to add. It has to be notified through
+/// [`Message::ArtifactsUploaded`](crate::messages::Message::ArtifactsUploaded) afterwards.
+pub async fn queue_upload(upload: PendingUpload) {
+    state()
+        .persistent
+        .write()
+        .await
+        .pending_uploads
+        .push(upload);
+    save_state().await;
+}
+
+/// Removes the queued uploads from the state, in the order they were queued.
+pub async fn take_pending_uploads() -> Vec<PendingUpload> {
+    let uploads = std::mem::take(&mut state().persistent.write().await.pending_uploads);
+    if !uploads.is_empty() {
+        save_state().await;
+    }
+    uploads
+}
+
+pub async fn build_package(
+    package: &Package,
+    build_time: i64,
+    files: Vec<String>,
+    commit: Option<String>,
+    checksums: HashMap<String, String>,
+) {
+    let mut state = state().persistent.write().await;
     if let Some(status) = state.package_status.get_mut(package) {
         status.build = Some(Build {
             time: build_time,
             files,
+            commit,
+            checksums,
         });
     }
     drop(state);
     save_state().await;
 }
 
-pub async fn track_package(package: &Package, dependencies: HashSet<Package>, is_dependency: bool) {
-    let mut state = STATE.persistent.write().await;
+/// Forgets the files of a package's build and its promoted builds, but keeps the build time so it
+/// doesn't get rebuilt. The package shows up as evicted until it gets built again.
+pub async fn evict_build(package: &Package) {
+    let mut state = state().persistent.write().await;
+    if let Some(status) = state.package_status.get_mut(package) {
+        if let Some(build) = status.build.as_mut() {
+            build.files.clear();
+            build.checksums.clear();
+        }
+        status.promoted.clear();
+    }
+    drop(state);
+    save_state().await;
+}
+
+pub async fn set_quarantined(package: &Package, since: Option<i64>) {
+    let mut state = state().persistent.write().await;
+    if let Some(status) = state.package_status.get_mut(package) {
+        status.quarantined_since = since;
+    }
+    drop(state);
+    save_state().await;
+}
+
+pub async fn quarantined_packages() -> HashMap<Package, i64> {
+    state()
+        .persistent
+        .read()
+        .await
+        .package_status
+        .iter()
+        .filter_map(|(pkg, info)| info.quarantined_since.map(|since| (pkg.clone(), since)))
+        .collect()
+}
+
+/// Packages whose last build attempt failed, but that aren't quarantined yet.
+pub async fn failing_packages() -> HashSet<Package> {
+    state()
+        .persistent
+        .read()
+        .await
+        .package_status
+        .iter()
+        .filter(|(_, info)| {
+            info.quarantined_since.is_none()
+                && info.last_failure.as_ref().is_some_and(|failure| {
+                    info.build
+                        .as_ref()
+                        .is_none_or(|build| build.time < failure.time)
+                })
+        })
+        .map(|(pkg, _)| pkg.clone())
+        .collect()
+}
+
+pub async fn set_out_of_date(package: &Package, flagged: Option<i64>) {
+    let mut state = state().persistent.write().await;
+    if let Some(status) = state.package_status.get_mut(package) {
+        status.out_of_date = flagged;
+    }
+    drop(state);
+    save_state().await;
+}
+
+pub async fn out_of_date_packages() -> HashMap<Package, i64> {
+    state()
+        .persistent
+        .read()
+        .await
+        .package_status
+        .iter()
+        .filter_map(|(pkg, info)| info.out_of_date.map(|flagged| (pkg.clone(), flagged)))
+        .collect()
+}
+
+/// Forgets the last failure of every package and releases all of them from quarantine.
+pub async fn clear_failures() {
+    let mut state = state().persistent.write().await;
+    for status in state.package_status.values_mut() {
+        status.last_failure = None;
+        status.quarantined_since = None;
+    }
+    drop(state);
+    save_state().await;
+}
+
+/// The packages whose build got evicted from the repository.
+pub async fn evicted_packages() -> HashSet<Package> {
+    state()
+        .persistent
+        .read()
+        .await
+        .package_status
+        .iter()
+        .filter(|(_, info)| info.build.as_ref().is_some_and(|build| build.files.is_empty()))
+        .map(|(package, _)| package.clone())
+        .collect()
+}
+
+/// Forgets the build of a package, so it counts as unbuilt until the next build succeeds.
+pub async fn clear_build(package: &Package) {
+    let mut state = state().persistent.write().await;
+    if let Some(status) = state.package_status.get_mut(package) {
+        status.build = None;
+    }
+    drop(state);
+    save_state().await;
+}
+
+/// Records the stage the build of a package entered. The first stage marks the start of the build.
+pub async fn set_build_progress(package: &Package, stage: BuildStage, time: i64) {
+    let mut ephemeral = state().ephemeral.write().await;
+    let started = ephemeral
+        .build_progress
+        .get(package)
+        .map_or(time, |progress| progress.started);
+    ephemeral.build_progress.insert(
+        package.to_string(),
+        BuildProgress {
+            stage,
+            started,
+            since: time,
+        },
+    );
+}
+
+pub async fn clear_build_progress(package: &Package) {
+    state()
+        .ephemeral
+        .write()
+        .await
+        .build_progress
+        .remove(package);
+}
+
+pub async fn build_progress() -> HashMap<Package, BuildProgress> {
+    state().ephemeral.read().await.build_progress.clone()
+}
+
+/// Pauses or resumes starting new builds. Returns whether that changed anything.
+pub async fn set_paused(paused: bool) -> bool {
+    let mut ephemeral = state().ephemeral.write().await;
+    let changed = ephemeral.paused != paused;
+    ephemeral.paused = paused;
+    changed
+}
+
+pub async fn is_paused() -> bool {
+    state().ephemeral.read().await.paused
+}
+
+/// Records that a build of the package got copied into another channel.
+pub async fn set_promoted(package: &Package, channel: &str, build: Build) {
+    let mut state = state().persistent.write().await;
+    if let Some(status) = state.package_status.get_mut(package) {
+        status.promoted.insert(channel.to_string(), build);
+    }
+    drop(state);
+    save_state().await;
+}
+
+pub async fn set_last_failure(package: &Package, failure: Failure) {
+    let mut state = state().persistent.write().await;
+    if let Some(status) = state.package_status.get_mut(package) {
+        status.last_failure = Some(failure);
+    }
+    drop(state);
+    save_state().await;
+}
+
+/// Replaces the overrides that are set in the update, after clearing all of them on a reset.
+pub async fn set_overrides(package: &Package, update: OverridesUpdate) {
+    let mut state = state().persistent.write().await;
+    if let Some(status) = state.package_status.get_mut(package) {
+        if update.reset {
+            status.memory_limit = None;
+            status.extra_build_args.clear();
+            status.answers.clear();
+            status.pkgbuild_additions.clear();
+        }
+        if let Some(memory_limit) = update.memory_limit {
+            status.memory_limit = Some(memory_limit);
+        }
+        if let Some(extra_build_args) = update.extra_build_args {
+            status.extra_build_args = extra_build_args;
+        }
+        if let Some(answers) = update.answers {
+            status.answers = answers;
+        }
+        if let Some(pkgbuild_additions) = update.pkgbuild_additions {
+            status.pkgbuild_additions = pkgbuild_additions;
+        }
+    }
+    drop(state);
+    save_state().await;
+}
+
+pub async fn track_package(
+    package: &Package,
+    dependencies: HashSet<Package>,
+    is_dependency: bool,
+    channel: &str,
+    source: PackageSource,
+) {
+    let mut state = state().persistent.write().await;
     state.package_status.insert(
         package.to_string(),
         PackageInfo {
             build: None,
             is_dependency,
             dependencies,
+            last_vcs_rebuild: None,
+            memory_limit: None,
+            extra_build_args: Vec::new(),
+            answers: Vec::new(),
+            pkgbuild_additions: Vec::new(),
+            last_failure: None,
+            quarantined_since: None,
+            out_of_date: None,
+            channel: channel.to_string(),
+            promoted: HashMap::new(),
+            source,
         },
     );
     drop(state);
     save_state().await;
 }
 
+/// Tracks a package built from uploaded sources. It has no known dependencies, paru installs the
+/// ones it needs during the build.
+pub async fn track_local_package(package: &Package, channel: &str) {
+    track_package(
+        package,
+        HashSet::new(),
+        false,
+        channel,
+        PackageSource::Local,
+    )
+    .await;
+}
+
+/// The packages built from uploaded sources rather than the AUR.
+pub async fn local_packages() -> HashSet<Package> {
+    state()
+        .persistent
+        .read()
+        .await
+        .package_status
+        .iter()
+        .filter(|(_, info)| info.source == PackageSource::Local)
+        .map(|(package, _)| package.clone())
+        .collect()
+}
+
+fn local_source_path(package: &Package) -> PathBuf {
+    config::state_dir()
+        .join(LOCAL_SOURCES_DIR)
+        .join(format!("{package}.tar"))
+}
+
+/// Keeps the uploaded sources of a local package, replacing the previous ones.
+pub async fn save_local_source(package: &Package, archive: &[u8]) -> Result<(), Error> {
+    let path = local_source_path(package);
+    let temp_path = path.with_extension("tar.tmp");
+    tokio::fs::create_dir_all(config::state_dir().join(LOCAL_SOURCES_DIR)).await?;
+    write(&temp_path, archive).await?;
+    rename(&temp_path, &path).await?;
+    Ok(())
+}
+
+pub async fn read_local_source(package: &Package) -> Result<Vec<u8>, Error> {
+    Ok(tokio::fs::read(local_source_path(package)).await?)
+}
+
 pub async fn tracked_packages() -> HashSet<Package> {
-    STATE
+    state()
         .persistent
         .read()
         .await
@@ -102,8 +558,25 @@ pub async fn tracked_packages() -> HashSet<Package> {
         .collect()
 }
 
-async fn all_dependencies() -> HashSet<Package> {
-    STATE
+pub async fn unbuilt_packages() -> HashSet<Package> {
+    state()
+        .persistent
+        .read()
+        .await
+        .package_status
+        .iter()
+        .filter_map(|(pkg, info)| {
+            if info.build.is_none() {
+                Some(pkg.clone())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+pub async fn all_dependencies() -> HashSet<Package> {
+    state()
         .persistent
         .read()
         .await
@@ -119,29 +592,149 @@ async fn all_dependencies() -> HashSet<Package> {
         .collect()
 }
 
-async fn required_dependencies() -> HashSet<Package> {
-    STATE
+/// Returns the packages some other tracked package depends on.
+pub async fn required_packages() -> HashSet<Package> {
+    state()
         .persistent
         .read()
         .await
         .package_status
-        .values()
-        .flat_map(|info| info.dependencies.clone())
+        .iter()
+        .flat_map(|(package, info)| {
+            info.dependencies
+                .iter()
+                .filter(move |dependency| *dependency != package)
+        })
+        .cloned()
         .collect()
 }
 
+/// Returns the tracked packages that depend on the given package.
+pub async fn reverse_dependencies(package: &Package) -> HashSet<Package> {
+    state()
+        .persistent
+        .read()
+        .await
+        .package_status
+        .iter()
+        .filter_map(|(pkg, info)| {
+            if info.dependencies.contains(package) {
+                Some(pkg.clone())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Walks the dependencies of a package transitively, returning the closure along with the direct
+/// dependencies of every package in it. Each package is only visited once, which breaks cycles.
+pub async fn dependency_closure(
+    package: &Package,
+) -> (Vec<Package>, HashMap<Package, Vec<Package>>) {
+    let state = state().persistent.read().await;
+    let mut closure = Vec::new();
+    let mut graph = HashMap::new();
+    visit_dependencies(package, &state.package_status, &mut closure, &mut graph);
+    closure.retain(|dependency| dependency != package);
+    (closure, graph)
+}
+
+/// Visits the dependencies before adding the package itself, so they come first in the closure.
+fn visit_dependencies(
+    package: &Package,
+    packages: &HashMap<Package, PackageInfo>,
+    closure: &mut Vec<Package>,
+    graph: &mut HashMap<Package, Vec<Package>>,
+) {
+    if graph.contains_key(package) {
+        return;
+    }
+
+    let mut dependencies: Vec<Package> = packages
+        .get(package)
+        .map(|info| info.dependencies.iter().cloned().collect())
+        .unwrap_or_default();
+    dependencies.sort();
+    graph.insert(package.clone(), dependencies.clone());
+    for dependency in &dependencies {
+        visit_dependencies(dependency, packages, closure, graph);
+    }
+    closure.push(package.clone());
+}
+
+/// The packages that can be built, which is the case once none of their dependencies are queued
+/// or being built, so they don't get built against a dependency that is about to be updated.
+pub async fn ready_packages(packages: &[Package], pending: &HashSet<Package>) -> HashSet<Package> {
+    let state = state().persistent.read().await;
+    packages
+        .iter()
+        .filter(|package| dependencies_met(package, &state.package_status, pending))
+        .cloned()
+        .collect()
+}
+
+fn dependencies_met(
+    package: &Package,
+    packages: &HashMap<Package, PackageInfo>,
+    pending: &HashSet<Package>,
+) -> bool {
+    packages.get(package).is_none_or(|info| {
+        info.dependencies
+            .iter()
+            .all(|dependency| dependency == package || !pending.contains(dependency))
+    })
+}
+
 pub async fn unneeded_dependencies() -> HashSet<Package> {
-    let all_dependencies = all_dependencies().await;
-    let required_dependencies = required_dependencies().await;
+    unneeded(&state().persistent.read().await.package_status)
+}
 
-    all_dependencies
-        .difference(&required_dependencies)
-        .map(String::clone)
+/// Packages tracked only as dependencies, which no tracked package depends on anymore.
+fn unneeded(package_status: &HashMap<Package, PackageInfo>) -> HashSet<Package> {
+    let required: HashSet<&Package> = package_status
+        .values()
+        .flat_map(|info| &info.dependencies)
+        .collect();
+
+    package_status
+        .iter()
+        .filter(|(package, info)| info.is_dependency && !required.contains(package))
+        .map(|(package, _)| package.clone())
+        .collect()
+}
+
+/// Marks tracked packages the user asked for explicitly as no longer being just dependencies, so
+/// they are kept once nothing depends on them anymore. Returns the packages that changed.
+pub async fn mark_as_requested(packages: &HashSet<Package>) -> HashSet<Package> {
+    let mut state = state().persistent.write().await;
+    let changed = mark_requested(&mut state.package_status, packages);
+    drop(state);
+    if !changed.is_empty() {
+        save_state().await;
+    }
+    changed
+}
+
+fn mark_requested(
+    package_status: &mut HashMap<Package, PackageInfo>,
+    packages: &HashSet<Package>,
+) -> HashSet<Package> {
+    packages
+        .iter()
+        .filter(|package| match package_status.get_mut(*package) {
+            Some(info) if info.is_dependency => {
+                info.is_dependency = false;
+                true
+            }
+            _ => false,
+        })
+        .cloned()
         .collect()
 }
 
 pub async fn get_build_times(packages: &HashSet<Package>) -> HashMap<Package, i64> {
-    STATE
+    state()
         .persistent
         .read()
         .await
@@ -158,8 +751,37 @@ pub async fn get_build_times(packages: &HashSet<Package>) -> HashMap<Package, i6
         .collect()
 }
 
+pub async fn package_info(package: &Package) -> Option<PackageInfo> {
+    state()
+        .persistent
+        .read()
+        .await
+        .package_status
+        .get(package)
+        .cloned()
+}
+
+pub async fn last_vcs_rebuild(package: &Package) -> Option<i64> {
+    state()
+        .persistent
+        .read()
+        .await
+        .package_status
+        .get(package)
+        .and_then(|info| info.last_vcs_rebuild)
+}
+
+pub async fn set_last_vcs_rebuild(package: &Package, time: i64) {
+    let mut state = state().persistent.write().await;
+    if let Some(status) = state.package_status.get_mut(package) {
+        status.last_vcs_rebuild = Some(time);
+    }
+    drop(state);
+    save_state().await;
+}
+
 pub async fn get_files(package: &Package) -> Vec<String> {
-    STATE
+    state()
         .persistent
         .read()
         .await
@@ -176,20 +798,60 @@ pub async fn get_files(package: &Package) -> Vec<String> {
         .collect()
 }
 
-pub async fn get_all_files() -> Vec<String> {
-    STATE
+pub async fn package_channels() -> HashMap<Package, String> {
+    state()
         .persistent
         .read()
         .await
         .package_status
         .iter()
-        .filter_map(|(_, info)| info.build.as_ref().map(|status| status.files.clone()))
-        .flatten()
+        .map(|(pkg, info)| (pkg.clone(), info.channel.clone()))
         .collect()
 }
 
+/// Returns the channel of a package, or the default one if it isn't tracked.
+pub async fn package_channel(package: &Package) -> String {
+    state()
+        .persistent
+        .read()
+        .await
+        .package_status
+        .get(package)
+        .map_or_else(default_channel, |info| info.channel.clone())
+}
+
+/// Returns the files of the builds in a channel, including the promoted ones.
+pub async fn channel_files(channel: &str) -> Vec<String> {
+    state()
+        .persistent
+        .read()
+        .await
+        .package_status
+        .values()
+        .filter_map(|info| {
+            if info.channel == channel {
+                info.build.as_ref()
+            } else {
+                info.promoted.get(channel)
+            }
+        })
+        .flat_map(|build| build.files.clone())
+        .collect()
+}
+
+/// Returns the build of a package in a channel, whether it got built or promoted there.
+pub async fn channel_build(package: &Package, channel: &str) -> Option<Build> {
+    let state = state().persistent.read().await;
+    let info = state.package_status.get(package)?;
+    if info.channel == channel {
+        info.build.clone()
+    } else {
+        info.promoted.get(channel).cloned()
+    }
+}
+
 pub async fn is_package_tracked(package: &Package) -> bool {
-    STATE
+    state()
         .persistent
         .read()
         .await
@@ -198,16 +860,29 @@ pub async fn is_package_tracked(package: &Package) -> bool {
 }
 
 pub async fn remove_packages(package: &HashSet<Package>) {
-    let mut persistent = STATE.persistent.write().await;
+    let mut persistent = state().persistent.write().await;
 
     for package in package {
-        persistent.package_status.remove(package);
+        if let Some(info) = persistent.package_status.remove(package) {
+            if info.source == PackageSource::Local {
+                remove_local_source(package);
+            }
+        }
     }
 
     drop(persistent);
     save_state().await;
 }
 
+fn remove_local_source(package: &Package) {
+    match std::fs::remove_file(local_source_path(package)) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            error!("Failed to delete the sources of {package}: {err}");
+        }
+        _ => (),
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("IO error: {0}")]
@@ -215,3 +890,96 @@ pub enum Error {
     #[error("IO error: {0}")]
     Deserialize(#[from] serde_json::Error),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package_info(dependencies: &[&str]) -> PackageInfo {
+        PackageInfo {
+            is_dependency: false,
+            dependencies: dependencies.iter().map(|dep| dep.to_string()).collect(),
+            build: Some(Build {
+                time: 0,
+                files: Vec::new(),
+                commit: None,
+                checksums: HashMap::new(),
+            }),
+            last_vcs_rebuild: None,
+            memory_limit: None,
+            extra_build_args: Vec::new(),
+            answers: Vec::new(),
+            pkgbuild_additions: Vec::new(),
+            last_failure: None,
+            quarantined_since: None,
+            out_of_date: None,
+            channel: default_channel(),
+            promoted: HashMap::new(),
+            source: PackageSource::Aur,
+        }
+    }
+
+    #[test]
+    fn waits_for_dependency_pending_rebuild() {
+        let packages = HashMap::from([
+            ("paru".to_string(), package_info(&["rustup"])),
+            ("rustup".to_string(), package_info(&[])),
+        ]);
+        let package = "paru".to_string();
+
+        let pending = HashSet::from(["paru".to_string(), "rustup".to_string()]);
+        assert!(!dependencies_met(&package, &packages, &pending));
+
+        let pending = HashSet::from(["paru".to_string()]);
+        assert!(dependencies_met(&package, &packages, &pending));
+    }
+
+    fn dependency_info(dependencies: &[&str]) -> PackageInfo {
+        PackageInfo {
+            is_dependency: true,
+            ..package_info(dependencies)
+        }
+    }
+
+    #[test]
+    fn explicitly_added_dependency_is_kept() {
+        let mut packages = HashMap::from([
+            ("paru".to_string(), package_info(&["rustup"])),
+            ("rustup".to_string(), dependency_info(&[])),
+        ]);
+
+        let requested = HashSet::from(["rustup".to_string()]);
+        assert_eq!(mark_requested(&mut packages, &requested), requested);
+        assert!(!packages["rustup"].is_dependency);
+
+        packages.remove("paru");
+        assert!(unneeded(&packages).is_empty());
+    }
+
+    #[test]
+    fn requested_package_that_becomes_a_dependency_is_kept() {
+        // Adding paru leaves the already tracked rustup as it is, so it stays a requested package.
+        let mut packages = HashMap::from([
+            ("paru".to_string(), package_info(&["rustup"])),
+            ("rustup".to_string(), package_info(&[])),
+        ]);
+
+        let requested = HashSet::from(["rustup".to_string()]);
+        assert!(mark_requested(&mut packages, &requested).is_empty());
+
+        packages.remove("paru");
+        assert!(unneeded(&packages).is_empty());
+    }
+
+    #[test]
+    fn dependency_nothing_depends_on_is_unneeded() {
+        let mut packages = HashMap::from([
+            ("paru".to_string(), package_info(&["rustup"])),
+            ("rustup".to_string(), dependency_info(&[])),
+        ]);
+        assert!(unneeded(&packages).is_empty());
+
+        packages.remove("paru");
+        assert_eq!(unneeded(&packages), HashSet::from(["rustup".to_string()]));
+    }
+}
@@ -6,6 +6,24 @@ use tokio::select;
 use tokio::time::sleep;
 use tokio::time::sleep as tokio_sleep;
 
+/// Set by tasks that ran into a condition the coordinator can't recover from.
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Asks for the coordinator to shut down, as if it received a signal.
+pub fn request_shutdown() {
+    SHUTDOWN_REQUESTED.store(true, Relaxed);
+}
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Relaxed)
+}
+
+/// Forgets a requested shutdown, so a test requesting one doesn't leak it into the others.
+#[cfg(test)]
+pub fn clear_shutdown_request() {
+    SHUTDOWN_REQUESTED.store(false, Relaxed);
+}
+
 pub struct StopToken {
     is_stopped: Arc<AtomicBool>,
     children: Vec<Arc<AtomicBool>>,
@@ -0,0 +1,99 @@
+use crate::stop_token::StopToken;
+use axum::extract::ConnectInfo;
+use axum::{Extension, Router};
+use hyper::server::conn::http1;
+use hyper_util::rt::TokioIo;
+use hyper_util::service::TowerToHyperService;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::net::TcpListener;
+use tokio::select;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+use tracing::debug;
+
+/// Reads the PEM encoded certificate chain and private key.
+pub fn load_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>, Error> {
+    let read_error = |path: &Path| {
+        let path = path.display().to_string();
+        move |err| Error::Read(path, err)
+    };
+
+    let mut cert_reader = BufReader::new(std::fs::File::open(cert).map_err(read_error(cert))?);
+    let certs: Vec<CertificateDer> = rustls_pemfile::certs(&mut cert_reader)
+        .collect::<Result<_, _>>()
+        .map_err(read_error(cert))?;
+    if certs.is_empty() {
+        return Err(Error::NoCertificate(cert.display().to_string()));
+    }
+
+    let mut key_reader = BufReader::new(std::fs::File::open(key).map_err(read_error(key))?);
+    let key: PrivateKeyDer = rustls_pemfile::private_key(&mut key_reader)
+        .map_err(read_error(key))?
+        .ok_or_else(|| Error::NoKey(key.display().to_string()))?;
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// Serves the router over HTTPS until the stop token is triggered. Connections that fail the
+/// handshake are only logged, as they are usually clients that don't trust the certificate.
+pub async fn serve(
+    listener: TcpListener,
+    router: Router,
+    config: Arc<ServerConfig>,
+    mut stop_token: StopToken,
+) {
+    let acceptor = TlsAcceptor::from(config);
+    loop {
+        let (stream, address) = select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    debug!("Failed to accept a connection: {err}");
+                    continue;
+                }
+            },
+            () = stop_token.wait() => break,
+        };
+
+        let acceptor = acceptor.clone();
+        let service =
+            TowerToHyperService::new(router.clone().layer(Extension(ConnectInfo(address))));
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    debug!("TLS handshake with {address} failed: {err}");
+                    return;
+                }
+            };
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                debug!("Connection to {address} failed: {err}");
+            }
+        });
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Failed to read {0}: {1}")]
+    Read(String, std::io::Error),
+    #[error("{0} contains no certificate")]
+    NoCertificate(String),
+    #[error("{0} contains no private key")]
+    NoKey(String),
+    #[error("Invalid certificate or key: {0}")]
+    Rustls(#[from] tokio_rustls::rustls::Error),
+}
@@ -1,136 +1,882 @@
-use crate::messages::Message;
-use crate::repository::REPO_DIR;
-use crate::stop_token::StopToken;
-use crate::{aur, config, state};
-use axum::extract::{DefaultBodyLimit, State};
-use axum::http::StatusCode;
+use crate::config::{Channel, Role, DEFAULT_CHANNEL};
+use crate::messages::{self, BuildPriority, Message};
+use crate::repository;
+use crate::state::{PendingAddition, PendingUpload};
+use crate::stop_token::{self, StopToken};
+use crate::{aur, config, orchestrator, scheduler, state, tls};
+use axum::body::{Body, Bytes};
+use axum::extract::multipart::{Field, MultipartError};
+use axum::extract::{
+    ConnectInfo, DefaultBodyLimit, Multipart, OriginalUri, Path as UrlPath, Query, Request, State,
+};
+use axum::http::{header, HeaderMap, Method, StatusCode, Uri};
+use axum::middleware::{self, Next};
+use axum::response::{Html, IntoResponse, Response};
 use axum::routing::{get, post};
 use axum::{Json, Router};
+use itertools::Itertools;
 use coordinator::{
-    AddPackages, AddPackagesResponse, Artifacts, RemovePackages, RemovePackagesResponse, Status,
+    checksum_hex, combine_for_display, AddPackages, AddPackagesResponse, ArtifactsMetadata,
+    BuildDetails, BuildFailure, BuildFailureReport, BuildProgressReport, DependencyClosure,
+    ErrorResponse, OverridesUpdate, PackageDetails, PackageOverrides, PackageSource, PromotePackage,
+    RebuildPackages, RebuildPackagesResponse, RemovePackages, RemovePackagesResponse,
+    ReverseDependencies, Status, StatusCounts, ARTIFACTS_FILE_FIELD, ARTIFACTS_METADATA_FIELD,
+    VERSION, WORKER_VERSION_HEADER,
 };
-use std::collections::HashSet;
+use openssl::sha::Sha256;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use time::macros::format_description;
+use time::OffsetDateTime;
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpListener;
 use tokio::sync::broadcast::Sender;
+use tokio::sync::RwLock;
+use tokio_util::io::ReaderStream;
 use tower_http::services::ServeDir;
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 use tracing::log::info;
 
+const DASHBOARD: &str = include_str!("dashboard.html");
+/// Uploads with files older than this were abandoned, so they get removed when space runs out.
+const STALE_UPLOAD_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// The request budget of every address that recently changed something.
+static RATE_LIMITS: LazyLock<Mutex<HashMap<IpAddr, TokenBucket>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Holds up to a minute's worth of requests and refills continuously.
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(per_minute: u32) -> Self {
+        Self {
+            tokens: f64::from(per_minute),
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, per_minute: u32) {
+        let elapsed = self.updated.elapsed().as_secs_f64();
+        let capacity = f64::from(per_minute);
+        self.tokens = (self.tokens + elapsed * capacity / 60.0).min(capacity);
+        self.updated = Instant::now();
+    }
+
+    /// Takes a token, returning false if there is none left.
+    fn take(&mut self, per_minute: u32) -> bool {
+        self.refill(per_minute);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_full(&self, per_minute: u32) -> bool {
+        self.updated.elapsed().as_secs() >= 60 || self.tokens >= f64::from(per_minute)
+    }
+}
+
 #[derive(Clone)]
 struct RequestState {
     sender: Sender<Message>,
+    worker_version: Arc<RwLock<Option<String>>>,
 }
 
 impl RequestState {
-    fn send_message(&self, message: Message) -> Result<(), StatusCode> {
-        if let Err(err) = self.sender.send(message) {
-            error!("Failed to send message: {err}");
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+    fn send_message(&self, message: Message) -> Result<(), ApiError> {
+        if messages::send_message(&self.sender, message).is_err() {
+            Err(ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "The coordinator failed to process the request internally",
+            ))
         } else {
             Ok(())
         }
     }
 }
 
+/// An error that gets sent to the client as an [`ErrorResponse`].
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = Json(ErrorResponse {
+            error: self.message,
+        });
+        (self.status, body).into_response()
+    }
+}
+
+impl From<MultipartError> for ApiError {
+    fn from(err: MultipartError) -> Self {
+        ApiError::new(err.status(), err.body_text())
+    }
+}
+
+impl From<aur::Error> for ApiError {
+    fn from(err: aur::Error) -> Self {
+        let status = match err {
+            aur::Error::Reqwest(_) | aur::Error::Deserialize(_) => StatusCode::BAD_GATEWAY,
+            aur::Error::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        Self::new(status, format!("Failed to query the AUR: {err}"))
+    }
+}
+
 pub async fn start(sender: Sender<Message>, mut stop_token: StopToken) {
-    let state = RequestState { sender };
-    let router = Router::new()
+    let state = RequestState {
+        sender,
+        worker_version: Arc::new(RwLock::new(None)),
+    };
+    let max_artifact_size = usize::try_from(config::max_artifact_size()).unwrap_or(usize::MAX);
+    let router = router(state.clone(), max_artifact_size);
+    let tls = match config::tls().map(|(cert, key)| tls::load_config(&cert, &key)) {
+        Some(Ok(tls)) => Some(tls),
+        Some(Err(err)) => {
+            error!("Failed to set up TLS: {err}");
+            stop_token::request_shutdown();
+            return;
+        }
+        None => None,
+    };
+
+    let address = (config::bind_address(), config::port());
+    let protocol = if tls.is_some() { "HTTPS" } else { "HTTP" };
+    info!(
+        "Starting web server on {}:{} ({protocol})",
+        address.0, address.1
+    );
+    let listener = match TcpListener::bind(address).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Failed to bind to {}:{}: {err}", address.0, address.1);
+            return;
+        }
+    };
+    if let Some(tls) = tls {
+        let workers = tokio::spawn(serve_workers(
+            worker_router(state, max_artifact_size),
+            stop_token.child(),
+        ));
+        tls::serve(listener, router, tls, stop_token).await;
+        let _ = workers.await;
+        info!("Stopped web server");
+        return;
+    }
+
+    serve_http(listener, router, stop_token).await;
+    info!("Stopped web server");
+}
+
+/// Serves the worker routes over plain HTTP, as the address the workers reach the coordinator at
+/// isn't covered by its certificate.
+async fn serve_workers(router: Router, stop_token: StopToken) {
+    let address = (config::bind_address(), config::worker_port());
+    info!(
+        "Starting web server for the workers on {}:{} (HTTP)",
+        address.0, address.1
+    );
+    match TcpListener::bind(address).await {
+        Ok(listener) => serve_http(listener, router, stop_token).await,
+        Err(err) => {
+            error!("Failed to bind to {}:{}: {err}", address.0, address.1);
+            stop_token::request_shutdown();
+        }
+    }
+}
+
+async fn serve_http(listener: TcpListener, router: Router, mut stop_token: StopToken) {
+    let router = router.into_make_service_with_connect_info::<SocketAddr>();
+    let server_result = axum::serve(listener, router)
+        .with_graceful_shutdown(async move { stop_token.wait().await })
+        .await;
+    if let Err(err) = server_result {
+        error!("Web server exited with error: {err}");
+    }
+}
+
+/// The routes of the coordinator. Artifact uploads bigger than `max_artifact_size` are refused.
+fn router(state: RequestState, max_artifact_size: usize) -> Router {
+    Router::new()
+        .route("/", get(dashboard))
         .route("/status", get(status))
+        .route("/config", get(show_config))
+        .route("/snapshot", get(repo_snapshot))
         .route("/packages/add", post(add_package))
         .route("/packages/remove", post(remove_package))
+        .route("/packages/rebuild", post(rebuild_packages))
+        .route("/packages/:name/details", get(package_details))
+        .route("/packages/:name/overrides", post(set_overrides))
         .route(
-            "/artifacts",
-            post(receive_artifacts).layer(DefaultBodyLimit::disable()),
+            "/packages/:name/local",
+            post(upload_local_package).layer(DefaultBodyLimit::max(max_artifact_size)),
         )
+        .route("/packages/:name/promote", post(promote_package))
+        .route("/packages/:name/rdeps", get(reverse_dependencies))
+        .route("/packages/:name/closure", get(dependency_closure))
+        .route("/failures/reset", post(reset_failures))
+        .route("/check-updates", post(check_updates))
+        .route("/pause", post(pause))
+        .route("/resume", post(resume))
+        .route_layer(middleware::from_fn(limit_changes))
+        // Limiting the workers would only lose finished builds.
+        .merge(worker_routes(max_artifact_size))
+        .route_layer(middleware::from_fn(reject_changes_on_replica))
         .with_state(state)
-        .nest_service("/repo", ServeDir::new(REPO_DIR));
+        .nest_service(
+            "/repo",
+            ServeDir::new(config::repo_dir()).fallback(get(repo_index)),
+        )
+}
 
-    let port = config::port();
-    info!("Starting web server on port {port}");
-    let listener = TcpListener::bind(format!("0.0.0.0:{port}")).await.unwrap();
-    let server_result = axum::serve(listener, router)
-        .with_graceful_shutdown(async move { stop_token.wait().await })
-        .await;
-    if let Err(err) = server_result {
-        error!("Web server exited with error: {err}");
+/// Only the routes the workers use, for the listener they get whilst serving HTTPS.
+fn worker_router(state: RequestState, max_artifact_size: usize) -> Router {
+    worker_routes(max_artifact_size)
+        .route_layer(middleware::from_fn(reject_changes_on_replica))
+        .with_state(state)
+}
+
+fn worker_routes(max_artifact_size: usize) -> Router<RequestState> {
+    Router::new()
+        .route("/build-failure", post(receive_build_failure))
+        .route("/build-progress", post(receive_build_progress))
+        .route(
+            "/artifacts",
+            post(receive_artifacts).layer(DefaultBodyLimit::max(max_artifact_size)),
+        )
+}
+
+/// Replicas only serve what the primary built, so they refuse every request that changes something.
+async fn reject_changes_on_replica(request: Request, next: Next) -> Response {
+    if config::role() == Role::Replica && request.method() != Method::GET {
+        return ApiError::new(
+            StatusCode::FORBIDDEN,
+            "This coordinator is a replica. Send changes to the primary instead",
+        )
+        .into_response();
     }
+    next.run(request).await
+}
 
-    info!("Stopped web server");
+/// Limits the requests that change something per address to `RATE_LIMIT` per minute, so a script
+/// gone wrong can't queue builds endlessly. Reading and the reports of workers are not limited.
+async fn limit_changes(request: Request, next: Next) -> Response {
+    let per_minute = config::rate_limit();
+    let address = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| address.ip());
+    let Some(address) = address.filter(|_| per_minute > 0 && request.method() != Method::GET)
+    else {
+        return next.run(request).await;
+    };
+
+    let allowed = {
+        let mut buckets = RATE_LIMITS.lock().unwrap_or_else(|err| err.into_inner());
+        // Full buckets are the same as no bucket, so they don't need to be kept around.
+        buckets.retain(|_, bucket| !bucket.is_full(per_minute));
+        buckets
+            .entry(address)
+            .or_insert_with(|| TokenBucket::new(per_minute))
+            .take(per_minute)
+    };
+    if !allowed {
+        warn!("Rate limited {address}");
+        return ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            format!("Too many requests. At most {per_minute} changes per minute are allowed"),
+        )
+        .into_response();
+    }
+    next.run(request).await
 }
 
 async fn add_package(
     state: State<RequestState>,
     Json(add): Json<AddPackages>,
-) -> Result<Json<AddPackagesResponse>, StatusCode> {
-    let package_info = aur::do_packages_exist(&add.packages).await.map_err(|err| {
-        error!("Failed to get packages from the AUR: {err}");
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+) -> Result<Json<AddPackagesResponse>, ApiError> {
+    validate_package_names(&add.packages)?;
+    let channel = requested_channel(add.channel.as_deref())?;
     let tracked_packages = state::tracked_packages().await;
-
-    let not_found: HashSet<String> = add
-        .packages
-        .difference(&package_info)
-        .map(String::to_owned)
-        .collect();
     let already_tracked: HashSet<String> = tracked_packages
         .intersection(&add.packages)
         .map(String::to_owned)
         .collect();
-    let to_be_added: HashSet<String> = add
+    if add.channel.is_some() {
+        check_channels(&already_tracked, &channel).await?;
+    }
+
+    // The dependencies are only resolved here, the scheduler tracks them from the queued graph.
+    let untracked: HashSet<String> = add
         .packages
         .difference(&tracked_packages)
-        .map(String::to_owned)
+        .cloned()
         .collect();
+    let mut graph = HashMap::new();
+    let found = if untracked.is_empty() {
+        HashSet::new()
+    } else if add.resolve_deps {
+        graph = aur::dependency_graph(&aur::RpcClient, &untracked, &tracked_packages)
+            .await
+            .map_err(|err| {
+                error!("Failed to resolve the dependencies of the new packages: {err}");
+                ApiError::from(err)
+            })?;
+        untracked
+            .iter()
+            .filter(|package| graph.contains_key(*package))
+            .cloned()
+            .collect()
+    } else {
+        aur::do_packages_exist(&untracked).await.map_err(|err| {
+            error!("Failed to get packages from the AUR: {err}");
+            ApiError::from(err)
+        })?
+    };
 
-    if !to_be_added.is_empty() {
-        state.send_message(Message::AddPackages(to_be_added.clone()))?;
+    let not_found: HashSet<String> = untracked.difference(&found).cloned().collect();
+    let mut to_be_added = found;
+    let mut rejected_limit = HashSet::new();
+    let max = config::max_tracked_packages();
+    if max > 0 {
+        let accepted =
+            scheduler::packages_within_limit(&to_be_added, &graph, &tracked_packages, max);
+        rejected_limit = to_be_added.difference(&accepted).cloned().collect();
+        if !rejected_limit.is_empty() {
+            warn!(
+                "Not adding {}, as more than {max} packages would be tracked",
+                combine_for_display(&rejected_limit)
+            );
+        }
+        to_be_added = accepted;
     }
+    // Without resolving dependencies every package maps to none.
+    let resolved: HashMap<String, HashSet<String>> = to_be_added
+        .iter()
+        .flat_map(|package| aur::dependency_closure(package, &graph, &tracked_packages))
+        .map(|package| {
+            let dependencies = graph.get(&package).cloned().unwrap_or_default();
+            (package, dependencies)
+        })
+        .collect();
+    let dependencies: HashSet<String> = resolved
+        .keys()
+        .filter(|package| !to_be_added.contains(*package))
+        .cloned()
+        .collect();
+
+    // Tracked packages are passed along too, so the scheduler keeps dependencies the user asked for.
+    if !to_be_added.is_empty() || !already_tracked.is_empty() {
+        state::queue_addition(PendingAddition {
+            packages: to_be_added.union(&already_tracked).cloned().collect(),
+            channel: channel.name,
+            resolve_deps: add.resolve_deps,
+            build_immediately: add.build_immediately,
+            resolved,
+        })
+        .await;
+        state.send_message(Message::AddPackages)?;
+    }
+
+    let suggestions = aur::suggest_packages(&not_found).await;
 
     Ok(Json(AddPackagesResponse {
-        added: to_be_added,
-        not_found,
-        already_tracked,
+        added: to_be_added.into_iter().collect(),
+        not_found: not_found.into_iter().collect(),
+        already_tracked: already_tracked.into_iter().collect(),
+        dependencies: dependencies.into_iter().collect(),
+        suggestions: suggestions.into_iter().collect(),
+        rejected_limit: rejected_limit.into_iter().collect(),
     }))
 }
 
+/// Refuses to add packages to a channel other than the one they are tracked in already, as they
+/// would silently stay where they are.
+async fn check_channels(tracked: &HashSet<String>, channel: &Channel) -> Result<(), ApiError> {
+    let mut elsewhere = Vec::new();
+    for package in tracked.iter().sorted() {
+        if let Some(info) = state::package_info(package).await {
+            if info.channel != channel.name {
+                elsewhere.push(format!("{package} is tracked in {}", info.channel));
+            }
+        }
+    }
+    if elsewhere.is_empty() {
+        return Ok(());
+    }
+    Err(ApiError::new(
+        StatusCode::CONFLICT,
+        format!(
+            "{}. Promote or remove them first to use {}",
+            elsewhere.join(", "),
+            channel.name
+        ),
+    ))
+}
+
+/// Whether tracking another package would exceed `MAX_TRACKED_PACKAGES`.
+async fn tracking_limit_reached() -> bool {
+    let max = config::max_tracked_packages();
+    max > 0 && state::tracked_packages().await.len() >= max
+}
+
+/// The channel with the name, or the default one if none was given.
+fn requested_channel(name: Option<&str>) -> Result<Channel, ApiError> {
+    match name {
+        Some(name) => config::channel(name).ok_or_else(|| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                format!("There is no channel named '{name}'"),
+            )
+        }),
+        None => Ok(config::default_channel()),
+    }
+}
+
+#[derive(Deserialize)]
+struct LocalPackageQuery {
+    channel: Option<String>,
+}
+
+/// Stores the uploaded tar archive of a PKGBUILD and its sources and builds it. The package gets
+/// tracked in the requested channel if it isn't yet, otherwise its sources are replaced.
+async fn upload_local_package(
+    state: State<RequestState>,
+    UrlPath(name): UrlPath<String>,
+    Query(query): Query<LocalPackageQuery>,
+    archive: Bytes,
+) -> Result<(), ApiError> {
+    validate_package_names(&HashSet::from([name.clone()]))?;
+    let channel = requested_channel(query.channel.as_deref())?;
+    if archive.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "The archive of the sources is empty",
+        ));
+    }
+    let info = state::package_info(&name).await;
+    if info
+        .as_ref()
+        .is_some_and(|info| info.source != PackageSource::Local)
+    {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            format!(
+                "{name} is tracked from the AUR. Remove it first to build it from local sources"
+            ),
+        ));
+    }
+    if info.is_none() && tracking_limit_reached().await {
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!(
+                "Tracking {name} would exceed the limit of {} packages",
+                config::max_tracked_packages()
+            ),
+        ));
+    }
+
+    state::save_local_source(&name, &archive)
+        .await
+        .map_err(|err| {
+            error!("Failed to store the sources of {name}: {err}");
+            ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to store the sources of {name}"),
+            )
+        })?;
+    if info.is_some() {
+        info!("Replaced the sources of {name}");
+        state::set_quarantined(&name, None).await;
+    } else {
+        state::track_local_package(&name, &channel.name).await;
+        info!("Added local package {name} to {}", channel.name);
+    }
+    state.send_message(Message::BuildPackage {
+        package: name,
+        priority: BuildPriority::Requested,
+    })?;
+    Ok(())
+}
+
+/// Rejects the request if a name could not belong to an Arch package, as the names end up in the
+/// arguments of paru and the names of containers.
+fn validate_package_names(packages: &HashSet<String>) -> Result<(), ApiError> {
+    let mut invalid: Vec<&String> = packages
+        .iter()
+        .filter(|package| !is_valid_package_name(package))
+        .collect();
+    if invalid.is_empty() {
+        return Ok(());
+    }
+
+    invalid.sort();
+    Err(ApiError::new(
+        StatusCode::BAD_REQUEST,
+        format!("Invalid package names: {}", invalid.iter().join(", ")),
+    ))
+}
+
+/// Package names consist of lowercase letters, digits and `@._+-`, but may not start with a
+/// hyphen or a dot.
+fn is_valid_package_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(['-', '.'])
+        && name.chars().all(|char| {
+            char.is_ascii_lowercase() || char.is_ascii_digit() || "@._+-".contains(char)
+        })
+}
+
+/// Streams the uploaded files to disk. They are written under a temporary name first, so
+/// an interrupted upload can't replace a file of the repository.
 async fn receive_artifacts(
     state: State<RequestState>,
-    Json(data): Json<Artifacts>,
-) -> Result<(), StatusCode> {
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<(), ApiError> {
+    check_worker_version(&state, &headers).await;
+
     let mut files = Vec::new();
-    for (name, data) in &data.files {
-        let file_name = sanitize_filename(name);
-        if let Err(err) = tokio::fs::write(
-            PathBuf::new().join(REPO_DIR).join(sanitize_filename(name)),
-            data,
-        )
+    let mut checksums = HashMap::new();
+    let tracked = state::tracked_packages().await;
+    let result = read_upload(&mut multipart, &mut files, &mut checksums)
         .await
-        {
-            error!("Failed to write artifact to disk: {err}");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        .and_then(|metadata| verify_checksums(&metadata, &checksums).map(|()| metadata))
+        .and_then(|metadata| verify_package_files(&metadata, &files, &tracked).map(|()| metadata));
+    let metadata = match result {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            remove_uploads(&files).await;
+            return Err(storage_full_prunes(&state, err));
+        }
+    };
+
+    let dir = repository::channel_dir(&repository::package_channel(&metadata.package_name).await);
+    for file in &files {
+        if let Err(err) = tokio::fs::rename(upload_path(file), dir.join(file)).await {
+            error!("Failed to move artifact into the repository: {err}");
+            remove_uploads(&files).await;
+            return Err(storage_full_prunes(&state, disk_error(file, &err)));
         }
-        files.push(file_name);
     }
 
     debug!(
         "Got artifacts for {}. Received {} files.",
-        data.package_name,
-        data.files.len()
+        metadata.package_name,
+        files.len()
     );
 
-    state.send_message(Message::ArtifactsUploaded {
-        package: data.package_name,
+    state::queue_upload(PendingUpload {
+        package: metadata.package_name,
         files,
-        build_time: data.build_time,
+        build_time: metadata.build_time,
+        commit: metadata.commit,
+        checksums,
+    })
+    .await;
+    state.send_message(Message::ArtifactsUploaded)
+}
+
+/// Reads the metadata and writes the files of an upload, adding their names to `files` and their
+/// SHA-256 to `checksums`.
+async fn read_upload(
+    multipart: &mut Multipart,
+    files: &mut Vec<String>,
+    checksums: &mut HashMap<String, String>,
+) -> Result<ArtifactsMetadata, ApiError> {
+    let mut metadata = None;
+    while let Some(field) = multipart.next_field().await? {
+        match field.name() {
+            Some(ARTIFACTS_METADATA_FIELD) => {
+                let bytes = field.bytes().await?;
+                let parsed = serde_json::from_slice(&bytes).map_err(|err| {
+                    ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid metadata: {err}"))
+                })?;
+                metadata = Some(parsed);
+            }
+            Some(ARTIFACTS_FILE_FIELD) => {
+                let file_name = sanitize_filename(field.file_name().unwrap_or_default());
+                files.push(file_name.clone());
+                let checksum = write_upload(field, &file_name).await?;
+                checksums.insert(file_name, checksum);
+            }
+            _ => (),
+        }
+    }
+
+    metadata.ok_or_else(|| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "The upload is missing its metadata",
+        )
     })
 }
 
+/// Checks that every file is a package built from the claimed package, so a worker can't slip
+/// other packages into the repository. Besides the package itself, only the packages its
+/// PKGBUILD names are accepted. Workers that don't send those get the split and debug packages
+/// named after the package with a suffix accepted, like `linux-headers` built from `linux`.
+/// Packages that are tracked on their own are never accepted from another build.
+fn verify_package_files(
+    metadata: &ArtifactsMetadata,
+    files: &[String],
+    tracked: &HashSet<String>,
+) -> Result<(), ApiError> {
+    let package = metadata.package_name.as_str();
+    let split_prefix = format!("{package}-");
+    let belongs_to_package = |name: &str| {
+        if name == package {
+            return true;
+        }
+        let built = if metadata.pkgnames.is_empty() {
+            name.starts_with(&split_prefix)
+        } else {
+            metadata.pkgnames.iter().any(|pkgname| pkgname == name)
+        };
+        built && !tracked.contains(name)
+    };
+    let foreign = files
+        .iter()
+        .filter(|file| !repository::package_name(file).is_some_and(belongs_to_package))
+        .sorted()
+        .join(", ");
+    if foreign.is_empty() {
+        Ok(())
+    } else {
+        error!("Received artifacts for {package} that don't belong to it: {foreign}");
+        Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("{foreign} not built from {package}"),
+        ))
+    }
+}
+
+/// Compares the checksums the worker computed with the ones of the received files, so files that
+/// got corrupted on the way don't end up in the repository.
+fn verify_checksums(
+    metadata: &ArtifactsMetadata,
+    checksums: &HashMap<String, String>,
+) -> Result<(), ApiError> {
+    let mismatched = checksums
+        .iter()
+        .filter(|(file, checksum)| {
+            metadata
+                .checksums
+                .get(*file)
+                .is_some_and(|expected| expected != *checksum)
+        })
+        .map(|(file, _)| file)
+        .sorted()
+        .join(", ");
+    if mismatched.is_empty() {
+        Ok(())
+    } else {
+        error!(
+            "Received corrupted artifacts for {}: {mismatched}",
+            metadata.package_name
+        );
+        Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("Checksum mismatch for {mismatched}"),
+        ))
+    }
+}
+
+/// Writes a file of an upload to disk, returning its SHA-256.
+async fn write_upload(mut field: Field<'_>, file_name: &str) -> Result<String, ApiError> {
+    let write_error = |err: std::io::Error| {
+        error!("Failed to write artifact to disk: {err}");
+        // The worker uploads the artifacts again, which might fit once abandoned ones are gone.
+        if err.kind() == ErrorKind::StorageFull {
+            remove_stale_uploads();
+        }
+        disk_error(file_name, &err)
+    };
+
+    let mut file = tokio::fs::File::create(upload_path(file_name))
+        .await
+        .map_err(write_error)?;
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = field.chunk().await? {
+        hasher.update(&chunk);
+        file.write_all(&chunk).await.map_err(write_error)?;
+    }
+    file.flush().await.map_err(write_error)?;
+    Ok(checksum_hex(hasher.finish()))
+}
+
+/// A full disk is answered with `507 Insufficient Storage`, so the worker can tell it apart from
+/// other failures.
+fn disk_error(file_name: &str, err: &std::io::Error) -> ApiError {
+    let status = if err.kind() == ErrorKind::StorageFull {
+        StatusCode::INSUFFICIENT_STORAGE
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    ApiError::new(
+        status,
+        format!("Failed to write {file_name} to disk: {err}"),
+    )
+}
+
+/// Has the repository evict old builds when the disk is full, so the worker's next attempt to
+/// upload its artifacts might fit.
+fn storage_full_prunes(state: &RequestState, err: ApiError) -> ApiError {
+    if err.status == StatusCode::INSUFFICIENT_STORAGE {
+        let _ = state.send_message(Message::RepositoryFull);
+    }
+    err
+}
+
+/// Removes the files of uploads that never finished, like ones cut off by a restart.
+fn remove_stale_uploads() {
+    let Ok(entries) = std::fs::read_dir(config::repo_dir()) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let abandoned = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age > STALE_UPLOAD_AGE));
+        if name.starts_with('.') && name.ends_with(".part") && abandoned {
+            info!("Removing the abandoned upload {name} to free up space");
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+}
+
+fn upload_path(file_name: &str) -> PathBuf {
+    config::repo_dir().join(format!(".{file_name}.part"))
+}
+
+/// Removes the temporary files of an upload that failed.
+async fn remove_uploads(files: &[String]) {
+    for file in files {
+        let _ = tokio::fs::remove_file(upload_path(file)).await;
+    }
+}
+
+/// Warns if the worker was built from a different version, as the image was likely not rebuilt.
+async fn check_worker_version(state: &RequestState, headers: &HeaderMap) {
+    let version = headers
+        .get(WORKER_VERSION_HEADER)
+        .and_then(|version| version.to_str().ok())
+        .map(String::from);
+    match &version {
+        Some(version) if version == VERSION => (),
+        Some(version) => warn!(
+            "The worker was built from {version}, but the coordinator from {VERSION}. The builder image might be outdated"
+        ),
+        None => warn!("The worker did not send its version. The builder image is probably outdated"),
+    }
+    *state.worker_version.write().await = version;
+}
+
+async fn receive_build_progress(Json(report): Json<BuildProgressReport>) -> Result<(), ApiError> {
+    let package = report.package_name;
+    if !state::is_package_tracked(&package).await {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("{package} is not tracked"),
+        ));
+    }
+
+    debug!("Build of {package} is {}", report.stage);
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    state::set_build_progress(&package, report.stage, now).await;
+    Ok(())
+}
+
+async fn receive_build_failure(Json(report): Json<BuildFailureReport>) -> Result<(), ApiError> {
+    let package = report.package_name;
+    if !state::is_package_tracked(&package).await {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("{package} is not tracked"),
+        ));
+    }
+
+    let failure = report.failure;
+    info!(
+        "Build of {package} failed, {}: '{}' exited with {}",
+        failure.reason,
+        failure.command,
+        failure
+            .exit_code
+            .map_or("a signal".to_string(), |code| format!("code {code}"))
+    );
+    state::set_last_failure(
+        &package,
+        state::Failure {
+            time: failure.time,
+            command: failure.command,
+            exit_code: failure.exit_code,
+            output: failure.output,
+            reason: failure.reason,
+        },
+    )
+    .await;
+    Ok(())
+}
+
+async fn reset_failures(state: State<RequestState>) -> Result<(), ApiError> {
+    state::clear_failures().await;
+    info!("Cleared the failures of all packages");
+    state.send_message(Message::FailuresReset)?;
+    Ok(())
+}
+
+/// Only queues the check, so the response doesn't wait for the AUR.
+async fn check_updates(state: State<RequestState>) -> Result<StatusCode, ApiError> {
+    state.send_message(Message::CheckForUpdates)?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Holds back new builds until [`resume`] is called. Running builds are left to finish.
+async fn pause() {
+    if state::set_paused(true).await {
+        info!("Paused builds. Running builds still finish");
+    }
+}
+
+/// Checks for updates right away, as the scheduler skipped them whilst paused.
+async fn resume(state: State<RequestState>) -> Result<(), ApiError> {
+    if state::set_paused(false).await {
+        info!("Resumed builds");
+        state.send_message(Message::CheckForUpdates)?;
+    }
+    Ok(())
+}
+
 async fn remove_package(
     state: State<RequestState>,
     Json(remove): Json<RemovePackages>,
-) -> Result<Json<RemovePackagesResponse>, StatusCode> {
+) -> Result<Json<RemovePackagesResponse>, ApiError> {
     let tracked_packages = state::tracked_packages().await;
     let not_tracked: HashSet<String> = remove
         .packages
@@ -148,17 +894,446 @@ async fn remove_package(
     }
 
     Ok(Json(RemovePackagesResponse {
-        removed: to_be_removed,
-        not_tracked,
+        removed: to_be_removed.into_iter().collect(),
+        not_tracked: not_tracked.into_iter().collect(),
     }))
 }
 
-async fn status() -> Json<Status> {
-    Json(Status {
-        packages: state::tracked_packages().await,
+/// Builds the packages right away, releasing them from quarantine. A clean rebuild removes the
+/// current builds from the repository first, so nothing refers to them should the build fail.
+async fn rebuild_packages(
+    state: State<RequestState>,
+    Json(rebuild): Json<RebuildPackages>,
+) -> Result<Json<RebuildPackagesResponse>, ApiError> {
+    validate_package_names(&rebuild.packages)?;
+    let tracked_packages = state::tracked_packages().await;
+    let not_tracked: HashSet<String> = rebuild
+        .packages
+        .difference(&tracked_packages)
+        .map(String::to_owned)
+        .collect();
+    let rebuilding: HashSet<String> = tracked_packages
+        .intersection(&rebuild.packages)
+        .map(String::to_owned)
+        .collect();
+
+    if rebuild.clean && !rebuilding.is_empty() {
+        state.send_message(Message::PurgeArtifacts(rebuilding.clone()))?;
+    }
+    for package in &rebuilding {
+        state::set_quarantined(package, None).await;
+        state.send_message(Message::BuildPackage {
+            package: package.clone(),
+            priority: BuildPriority::Requested,
+        })?;
+    }
+
+    Ok(Json(RebuildPackagesResponse {
+        rebuilding: rebuilding.into_iter().collect(),
+        not_tracked: not_tracked.into_iter().collect(),
+    }))
+}
+
+async fn package_details(
+    UrlPath(name): UrlPath<String>,
+) -> Result<Json<PackageDetails>, ApiError> {
+    let Some(info) = state::package_info(&name).await else {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("{name} is not tracked"),
+        ));
+    };
+
+    Ok(Json(PackageDetails {
+        name,
+        is_dependency: info.is_dependency,
+        dependencies: info.dependencies.into_iter().collect(),
+        build: info.build.map(build_details),
+        overrides: PackageOverrides {
+            memory_limit: info.memory_limit,
+            extra_build_args: info.extra_build_args,
+            answers: info.answers,
+            pkgbuild_additions: info.pkgbuild_additions,
+        },
+        quarantined_since: info.quarantined_since,
+        out_of_date: info.out_of_date,
+        channel: info.channel,
+        promoted: info
+            .promoted
+            .into_iter()
+            .map(|(channel, build)| (channel, build_details(build)))
+            .collect(),
+        source: info.source,
+        last_failure: info.last_failure.map(|failure| BuildFailure {
+            time: failure.time,
+            command: failure.command,
+            exit_code: failure.exit_code,
+            output: failure.output,
+            reason: failure.reason,
+        }),
+    }))
+}
+
+fn build_details(build: state::Build) -> BuildDetails {
+    BuildDetails {
+        time: build.time,
+        files: build.files,
+        commit: build.commit,
+        checksums: build.checksums.into_iter().collect(),
+    }
+}
+
+async fn set_overrides(
+    UrlPath(name): UrlPath<String>,
+    Json(overrides): Json<OverridesUpdate>,
+) -> Result<(), ApiError> {
+    if !state::is_package_tracked(&name).await {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("{name} is not tracked"),
+        ));
+    }
+
+    state::set_overrides(&name, overrides).await;
+    info!("Updated the overrides of {name}");
+    Ok(())
+}
+
+/// Validates the promotion, which the repository then carries out.
+async fn promote_package(
+    state: State<RequestState>,
+    UrlPath(name): UrlPath<String>,
+    Json(promote): Json<PromotePackage>,
+) -> Result<(), ApiError> {
+    let Some(info) = state::package_info(&name).await else {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("{name} is not tracked"),
+        ));
+    };
+    for channel in [&promote.from, &promote.to] {
+        if config::channel(channel).is_none() {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                format!("There is no channel named '{channel}'"),
+            ));
+        }
+    }
+    if promote.from == promote.to || promote.to == info.channel {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("{name} can not be promoted to {}", promote.to),
+        ));
+    }
+    let has_build = state::channel_build(&name, &promote.from)
+        .await
+        .is_some_and(|build| !build.files.is_empty());
+    if !has_build {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            format!("{name} has no successful build in {}", promote.from),
+        ));
+    }
+
+    state.send_message(Message::PromotePackage {
+        package: name,
+        from: promote.from,
+        to: promote.to,
     })
 }
 
+async fn reverse_dependencies(
+    UrlPath(name): UrlPath<String>,
+) -> Result<Json<ReverseDependencies>, ApiError> {
+    if !state::is_package_tracked(&name).await {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("{name} is not tracked"),
+        ));
+    }
+
+    let required_by = state::reverse_dependencies(&name).await;
+    Ok(Json(ReverseDependencies {
+        name,
+        required_by: required_by.into_iter().collect(),
+    }))
+}
+
+async fn dependency_closure(
+    UrlPath(name): UrlPath<String>,
+) -> Result<Json<DependencyClosure>, ApiError> {
+    if !state::is_package_tracked(&name).await {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("{name} is not tracked"),
+        ));
+    }
+
+    let (closure, dependencies) = state::dependency_closure(&name).await;
+    Ok(Json(DependencyClosure {
+        name,
+        closure,
+        dependencies: dependencies.into_iter().collect(),
+    }))
+}
+
+/// A page showing the state of the packages, which polls the other endpoints by itself.
+async fn dashboard() -> Html<&'static str> {
+    Html(DASHBOARD)
+}
+
+#[derive(Deserialize)]
+struct StatusQuery {
+    /// Answers with a [`StatusCounts`] instead of the whole status
+    #[serde(default)]
+    count_only: bool,
+    /// A glob pattern the packages in the status have to match
+    filter: Option<String>,
+}
+
+async fn status(
+    state: State<RequestState>,
+    Query(query): Query<StatusQuery>,
+) -> Result<Response, ApiError> {
+    let filter = query
+        .filter
+        .as_deref()
+        .map(glob::Pattern::new)
+        .transpose()
+        .map_err(|err| ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid filter: {err}")))?;
+
+    let mut status = current_status(&state).await;
+    if let Some(filter) = filter {
+        filter_status(&mut status, &filter);
+    }
+    if query.count_only {
+        Ok(Json(StatusCounts::from(&status)).into_response())
+    } else {
+        Ok(Json(status).into_response())
+    }
+}
+
+/// Leaves only the packages matching the pattern in the status.
+fn filter_status(status: &mut Status, filter: &glob::Pattern) {
+    for packages in [
+        &mut status.packages,
+        &mut status.dependencies,
+        &mut status.evicted,
+        &mut status.quarantined,
+        &mut status.out_of_date,
+        &mut status.local,
+        &mut status.failing,
+        &mut status.building,
+    ] {
+        packages.retain(|package| filter.matches(package));
+    }
+    status.progress.retain(|package, _| filter.matches(package));
+}
+
+async fn current_status(state: &RequestState) -> Status {
+    let building = orchestrator::active_builds();
+    let progress = state::build_progress()
+        .await
+        .into_iter()
+        .filter(|(package, _)| building.contains(package))
+        .collect();
+    Status {
+        packages: state::tracked_packages().await.into_iter().collect(),
+        dependencies: state::all_dependencies().await.into_iter().collect(),
+        evicted: state::evicted_packages().await.into_iter().collect(),
+        quarantined: state::quarantined_packages().await.into_keys().collect(),
+        out_of_date: state::out_of_date_packages().await.into_keys().collect(),
+        local: state::local_packages().await.into_iter().collect(),
+        worker_version: state.worker_version.read().await.clone(),
+        docker_unavailable: !orchestrator::docker_available(),
+        failing: state::failing_packages().await.into_iter().collect(),
+        building: building.into_iter().collect(),
+        repository: repository::stats().await,
+        progress,
+        paused: state::is_paused().await,
+        outside_build_window: orchestrator::outside_build_window(),
+    }
+}
+
+async fn show_config() -> Json<serde_json::Value> {
+    Json(config::to_json())
+}
+
+#[derive(Deserialize)]
+struct SnapshotQuery {
+    channel: Option<String>,
+}
+
+/// Streams a tar archive of the repository, or of a single channel, so it can be mirrored by a
+/// machine that can't reach the coordinator. The archive is written by `tar` as it is sent.
+async fn repo_snapshot(Query(query): Query<SnapshotQuery>) -> Result<Response, ApiError> {
+    let mut command = tokio::process::Command::new("tar");
+    // Uploads that are still being received aren't part of the repository yet.
+    command.arg("--exclude=./.*.part");
+    let (dir, name) = match query.channel.as_deref() {
+        None => (config::repo_dir(), "repository".to_string()),
+        Some(name) => {
+            let channel = requested_channel(Some(name))?;
+            // The other channels are directories inside the default one.
+            if channel.name == DEFAULT_CHANNEL {
+                for other in config::channels() {
+                    if other.name != DEFAULT_CHANNEL {
+                        command.arg(format!("--exclude=./{}", other.name));
+                    }
+                }
+            }
+            (repository::channel_dir(&channel), channel.repo_name)
+        }
+    };
+    let mut child = command
+        .arg("-cf")
+        .arg("-")
+        .arg("-C")
+        .arg(&dir)
+        .arg(".")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|err| {
+            error!("Failed to start tar for a snapshot: {err}");
+            ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to create the snapshot",
+            )
+        })?;
+    let Some(archive) = child.stdout.take() else {
+        return Err(ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to create the snapshot",
+        ));
+    };
+    // Should the client go away, tar stops on its own once it can't write anymore.
+    tokio::spawn(async move {
+        match child.wait().await {
+            Ok(status) if !status.success() => warn!("tar failed to create a snapshot: {status}"),
+            Err(err) => warn!("Failed to wait for tar: {err}"),
+            Ok(_) => (),
+        }
+    });
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-tar".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{name}.tar\""),
+            ),
+        ],
+        Body::from_stream(ReaderStream::new(archive)),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+struct IndexQuery {
+    format: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RepoEntry {
+    name: String,
+    size: u64,
+    /// Unix timestamp of the last modification
+    modified: i64,
+}
+
+/// Lists the files of a channel's repository, as JSON when asked for with `?format=json`. Only
+/// answers requests for the directories of channels, as this is also where requests for missing
+/// files end up.
+async fn repo_index(
+    uri: Uri,
+    OriginalUri(original_uri): OriginalUri,
+    Query(query): Query<IndexQuery>,
+) -> Result<Response, ApiError> {
+    let channel = match uri.path().trim_matches('/') {
+        "" => Some(config::default_channel()),
+        name if name != DEFAULT_CHANNEL => config::channel(name),
+        _ => None,
+    };
+    let Some(channel) = channel else {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("{} does not exist", uri.path()),
+        ));
+    };
+    // The links are relative, so they keep working behind a proxy serving the coordinator below
+    // a base path. Without a trailing slash they are relative to the parent directory. The `./`
+    // keeps an epoch in a file name from being read as a URL scheme.
+    let prefix = match original_uri.path().rsplit_once('/') {
+        Some((_, "")) | None => "./".to_string(),
+        Some((_, directory)) => format!("{directory}/"),
+    };
+
+    let mut entries = Vec::new();
+    let mut dir = tokio::fs::read_dir(repository::channel_dir(&channel))
+        .await
+        .map_err(|err| {
+            ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to read the repository: {err}"),
+            )
+        })?;
+    while let Ok(Some(entry)) = dir.next_entry().await {
+        let Ok(metadata) = entry.metadata().await else {
+            continue;
+        };
+        let mut name = entry.file_name().to_string_lossy().to_string();
+        if metadata.is_dir() {
+            name.push('/');
+        }
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |duration| {
+                duration.as_secs().try_into().unwrap_or(i64::MAX)
+            });
+        entries.push(RepoEntry {
+            name,
+            size: metadata.len(),
+            modified,
+        });
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    if query.format.as_deref() == Some("json") {
+        return Ok(Json(entries).into_response());
+    }
+
+    let format = format_description!("[year]-[month]-[day] [hour]:[minute]");
+    let mut rows = String::new();
+    for entry in entries {
+        let name = escape_html(&entry.name);
+        let modified = OffsetDateTime::from_unix_timestamp(entry.modified)
+            .ok()
+            .and_then(|time| time.format(format).ok())
+            .unwrap_or_default();
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{prefix}{name}\">{name}</a></td><td>{}</td><td>{modified}</td></tr>\n",
+            entry.size
+        ));
+    }
+    Ok(Html(format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>{repo}</title></head>\n<body>\n<h1>{repo}</h1>\n\
+         <table>\n<tr><th>Name</th><th>Size</th><th>Modified (UTC)</th></tr>\n{rows}</table>\n\
+         </body>\n</html>\n",
+        repo = escape_html(&channel.repo_name),
+    ))
+    .into_response())
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
 fn sanitize_filename(file_name: &str) -> String {
     Path::new(file_name)
         .file_name()
@@ -166,3 +1341,657 @@ fn sanitize_filename(file_name: &str) -> String {
         .to_string_lossy()
         .to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stop_token::StopToken;
+    use crate::{repository, scheduler};
+    use axum::body::to_bytes;
+    use std::future::Future;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration;
+    use tokio::sync::broadcast::channel;
+    use tower::ServiceExt;
+
+    /// Stands in for `repo-add`, writing the file names into a database that `bsdtar` can read.
+    const FAKE_REPO_ADD: &str = r#"#!/bin/sh
+while [ "${1#--}" != "$1" ]; do shift; done
+db="$1"
+shift
+mkdir -p "$db.entries"
+for file in "$@"; do
+    mkdir -p "$db.entries/${file%%.pkg.tar*}"
+    printf '%%FILENAME%%\n%s\n' "$file" > "$db.entries/${file%%.pkg.tar*}/desc"
+done
+cd "$db.entries" && ls | bsdtar -cf "../$db" -T -
+"#;
+
+    /// The directories and the AUR the tests use. The config is only read once, so every test
+    /// that reads it has to set this up first.
+    struct TestEnvironment {
+        repo_dir: PathBuf,
+    }
+
+    /// Set as a secret of the config, which must never be served.
+    const REGISTRY_PASSWORD: &str = "registry-secret";
+
+    static TEST_ENVIRONMENT: LazyLock<TestEnvironment> = LazyLock::new(|| {
+        let dir = std::env::temp_dir().join(format!("archie-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (state_dir, repo_dir, bin_dir) = (dir.join("state"), dir.join("repo"), dir.join("bin"));
+        for dir in [&state_dir, &repo_dir, &bin_dir] {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+        let repo_add = bin_dir.join("repo-add");
+        std::fs::write(&repo_add, FAKE_REPO_ADD).unwrap();
+        std::fs::set_permissions(&repo_add, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let aur = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let aur_url = format!("http://{}/rpc/v5", aur.local_addr().unwrap());
+        // Every test has its own runtime, so the AUR gets one that outlives them.
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async move {
+                aur.set_nonblocking(true).unwrap();
+                let aur = TcpListener::from_std(aur).unwrap();
+                let router = Router::new().route("/rpc/v5/info", get(aur_info));
+                axum::serve(aur, router).await.unwrap();
+            });
+        });
+        let path = std::env::var("PATH").unwrap_or_default();
+        std::env::set_var("PATH", format!("{}:{path}", bin_dir.display()));
+        std::env::set_var("STATE_DIR", &state_dir);
+        std::env::set_var("REPO_DIR", &repo_dir);
+        std::env::set_var("AUR_RPC_URL", aur_url);
+        std::env::set_var("NO_PROXY", "127.0.0.1");
+        std::env::set_var("REGISTRY_PASSWORD", REGISTRY_PASSWORD);
+        std::env::set_var("CHANNELS", "testing");
+        TestEnvironment { repo_dir }
+    });
+
+    fn request_state(sender: Sender<Message>) -> RequestState {
+        LazyLock::force(&TEST_ENVIRONMENT);
+        RequestState {
+            sender,
+            worker_version: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    /// Answers like the AUR, knowing only paru and the packages starting with `flood-`.
+    async fn aur_info(uri: Uri) -> Json<serde_json::Value> {
+        let query = uri.query().unwrap_or_default();
+        let results: Vec<_> = query
+            .split('&')
+            .filter_map(|arg| {
+                arg.strip_prefix("arg[]=")
+                    .or_else(|| arg.strip_prefix("arg%5B%5D="))
+            })
+            .filter(|name| *name == "paru" || name.starts_with("flood-"))
+            .map(|name| {
+                serde_json::json!({
+                    "Name": name,
+                    "LastModified": 100,
+                    "OutOfDate": null,
+                    "Depends": [],
+                })
+            })
+            .collect();
+        Json(serde_json::json!({ "results": results }))
+    }
+
+    fn add_request(packages: &str) -> Request<Body> {
+        Request::post("/packages/add")
+            .header("content-type", "application/json")
+            .body(Body::from(format!(r#"{{"packages": [{packages}]}}"#)))
+            .unwrap()
+    }
+
+    /// Uploads a file like a worker would after building the package.
+    fn upload_request(package: &str, file: &str) -> Request<Body> {
+        let metadata = serde_json::json!({ "package_name": package, "build_time": 200 });
+        let boundary = "archie";
+        let body = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"{ARTIFACTS_METADATA_FIELD}\"\r\n\r\n\
+             {metadata}\r\n--{boundary}\r\nContent-Disposition: form-data; \
+             name=\"{ARTIFACTS_FILE_FIELD}\"; filename=\"{file}\"\r\n\r\npackage\r\n--{boundary}--\r\n"
+        );
+        Request::post("/artifacts")
+            .header(
+                "content-type",
+                format!("multipart/form-data; boundary={boundary}"),
+            )
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    /// Reads the file names in the database of the default channel.
+    fn repository_files() -> String {
+        let db = TEST_ENVIRONMENT.repo_dir.join("aur.db.tar.zst");
+        let desc = std::process::Command::new("bsdtar")
+            .arg("-xOf")
+            .arg(db)
+            .arg("*/desc")
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&desc.stdout).to_string()
+    }
+
+    async fn wait_until<F: Future<Output = bool>>(condition: impl Fn() -> F) {
+        for _ in 0..200 {
+            if condition().await {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("Timed out waiting for the coordinator");
+    }
+
+    #[test]
+    fn accepts_package_names() {
+        for name in [
+            "paru",
+            "paru-bin",
+            "libc++",
+            "python3.12",
+            "foo@bar",
+            "a_b",
+            "0ad",
+        ] {
+            assert!(is_valid_package_name(name), "{name} should be valid");
+        }
+    }
+
+    #[test]
+    fn rejects_package_names() {
+        for name in [
+            "", "-paru", ".paru", "Paru", "paru bin", "paru;rm", "../paru", "päru",
+        ] {
+            assert!(!is_valid_package_name(name), "{name} should be invalid");
+        }
+    }
+
+    #[test]
+    fn lists_invalid_package_names() {
+        let packages = HashSet::from(["paru".to_string(), "B".to_string(), "-a".to_string()]);
+        let err = validate_package_names(&packages).unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        assert_eq!(err.message, "Invalid package names: -a, B");
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_artifacts() {
+        let (sender, _receiver) = channel(1);
+        let state = request_state(sender);
+        let boundary = "archie";
+        let body = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"{ARTIFACTS_FILE_FIELD}\"; \
+             filename=\"paru.pkg.tar.zst\"\r\n\r\n{}\r\n--{boundary}--\r\n",
+            "0".repeat(1024)
+        );
+        let request = Request::post("/artifacts")
+            .header(
+                "content-type",
+                format!("multipart/form-data; boundary={boundary}"),
+            )
+            .body(Body::from(body))
+            .unwrap();
+
+        let response = router(state, 64).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    fn metadata(package: &str, pkgnames: &[&str]) -> ArtifactsMetadata {
+        ArtifactsMetadata {
+            package_name: package.to_string(),
+            build_time: 0,
+            commit: None,
+            checksums: HashMap::new(),
+            pkgnames: pkgnames.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn accepts_files_of_the_package() {
+        let files = [
+            "linux-6.12.1-1-x86_64.pkg.tar.zst",
+            "linux-headers-6.12.1-1-x86_64.pkg.tar.zst",
+        ]
+        .map(String::from);
+        let tracked = HashSet::from(["linux".to_string()]);
+        assert!(verify_package_files(&metadata("linux", &[]), &files, &tracked).is_ok());
+        let pkgnames = ["linux", "linux-headers", "linux-debug"];
+        assert!(verify_package_files(&metadata("linux", &pkgnames), &files, &tracked).is_ok());
+    }
+
+    #[test]
+    fn rejects_packages_the_pkgbuild_does_not_build() {
+        let files = [
+            "python-3.13.1-1-x86_64.pkg.tar.zst",
+            "python-requests-2.32.3-1-any.pkg.tar.zst",
+        ]
+        .map(String::from);
+        let tracked = HashSet::from(["python".to_string()]);
+
+        let err =
+            verify_package_files(&metadata("python", &["python"]), &files, &tracked).unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        assert!(err.message.contains("python-requests"));
+    }
+
+    #[test]
+    fn rejects_packages_tracked_on_their_own() {
+        let files = [
+            "linux-6.12.1-1-x86_64.pkg.tar.zst",
+            "linux-firmware-20241210-1-any.pkg.tar.zst",
+        ]
+        .map(String::from);
+        let tracked = HashSet::from(["linux".to_string(), "linux-firmware".to_string()]);
+
+        // Neither listing it in the PKGBUILD nor naming it after the package is enough.
+        let pkgnames = ["linux", "linux-firmware"];
+        assert!(verify_package_files(&metadata("linux", &pkgnames), &files, &tracked).is_err());
+        assert!(verify_package_files(&metadata("linux", &[]), &files, &tracked).is_err());
+    }
+
+    #[test]
+    fn full_disk_is_reported_as_insufficient_storage() {
+        let full = std::io::Error::from(ErrorKind::StorageFull);
+        let denied = std::io::Error::from(ErrorKind::PermissionDenied);
+
+        assert_eq!(
+            disk_error("paru-2.0.4-1-x86_64.pkg.tar.zst", &full).status,
+            StatusCode::INSUFFICIENT_STORAGE
+        );
+        assert_eq!(
+            disk_error("paru-2.0.4-1-x86_64.pkg.tar.zst", &denied).status,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[test]
+    fn rejects_spoofed_files() {
+        let files = [
+            "paru-2.0.4-1-x86_64.pkg.tar.zst",
+            "pacman-7.0.0-1-x86_64.pkg.tar.zst",
+        ]
+        .map(String::from);
+        let tracked = HashSet::from(["paru".to_string()]);
+        let err = verify_package_files(&metadata("paru", &[]), &files, &tracked).unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        let files = ["paru.tar.gz".to_string()];
+        assert!(verify_package_files(&metadata("paru", &[]), &files, &tracked).is_err());
+    }
+
+    #[tokio::test]
+    async fn details_of_packages_named_like_routes_are_served() {
+        let (sender, _receiver) = channel(1);
+        let request = Request::get("/packages/add/details")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = router(request_state(sender), 64)
+            .oneshot(request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = to_bytes(response.into_body(), 1024).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("add is not tracked"));
+    }
+
+    #[tokio::test]
+    async fn repository_index_links_are_relative() {
+        let (sender, _receiver) = channel(1);
+        let router = router(request_state(sender), 64);
+        std::fs::write(TEST_ENVIRONMENT.repo_dir.join("index-1:1.0-1.txt"), "").unwrap();
+
+        for (path, link) in [
+            ("/repo/", "./index-1:1.0-1.txt"),
+            ("/repo", "repo/index-1:1.0-1.txt"),
+        ] {
+            let request = Request::get(path).body(Body::empty()).unwrap();
+            let response = router.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{path}");
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert!(
+                String::from_utf8_lossy(&body).contains(&format!("href=\"{link}\"")),
+                "{path}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn worker_listener_only_serves_workers() {
+        let (sender, _receiver) = channel(1);
+        let router = worker_router(request_state(sender), 64);
+
+        let request = Request::post("/packages/remove")
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"packages": ["paru"]}"#))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let request = Request::post("/build-progress")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_ne!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn workers_are_not_rate_limited() {
+        let (sender, _receiver) = channel(1);
+        let router = router(request_state(sender), 64);
+        let address = ConnectInfo(SocketAddr::from(([10, 21, 17, 1], 40000)));
+        let request = |path: &str| {
+            let mut request = Request::post(path).body(Body::empty()).unwrap();
+            request.extensions_mut().insert(address);
+            request
+        };
+
+        for _ in 0..=config::rate_limit() {
+            let response = router
+                .clone()
+                .oneshot(request("/build-progress"))
+                .await
+                .unwrap();
+            assert_ne!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        }
+        for _ in 0..config::rate_limit() {
+            let response = router
+                .clone()
+                .oneshot(request("/packages/remove"))
+                .await
+                .unwrap();
+            assert_ne!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        }
+        let response = router.oneshot(request("/packages/remove")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn packages_not_in_the_aur_are_not_added() {
+        let (sender, mut receiver) = channel(8);
+        let router = router(request_state(sender), 64);
+        let request = Request::post("/packages/add")
+            .header("content-type", "application/json")
+            .body(Body::from(
+                r#"{"packages": ["paru-typo"], "resolve_deps": false}"#,
+            ))
+            .unwrap();
+
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: AddPackagesResponse =
+            serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap())
+                .unwrap();
+        assert!(response.added.is_empty());
+        assert!(response.not_found.contains("paru-typo"));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn overrides_that_are_left_out_keep_their_value() {
+        let (sender, _receiver) = channel(8);
+        let router = router(request_state(sender), 64);
+        let package = "flood-overrides".to_string();
+        state::track_package(
+            &package,
+            HashSet::new(),
+            false,
+            DEFAULT_CHANNEL,
+            PackageSource::Aur,
+        )
+        .await;
+
+        for body in [
+            r#"{"memory_limit": 1024, "answers": ["y"]}"#,
+            r#"{"extra_build_args": ["--nocheck"]}"#,
+        ] {
+            let request = Request::post("/packages/flood-overrides/overrides")
+                .header("content-type", "application/json")
+                .body(Body::from(body))
+                .unwrap();
+            let response = router.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let info = state::package_info(&package).await.unwrap();
+        assert_eq!(info.memory_limit, Some(1024));
+        assert_eq!(info.answers, vec!["y".to_string()]);
+        assert_eq!(info.extra_build_args, vec!["--nocheck".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn adding_to_another_channel_is_refused() {
+        let (sender, _receiver) = channel(8);
+        let router = router(request_state(sender), 64);
+        let package = "flood-channel".to_string();
+        state::track_package(
+            &package,
+            HashSet::new(),
+            false,
+            DEFAULT_CHANNEL,
+            PackageSource::Aur,
+        )
+        .await;
+        let request = Request::post("/packages/add")
+            .header("content-type", "application/json")
+            .body(Body::from(
+                r#"{"packages": ["flood-channel"], "channel": "testing"}"#,
+            ))
+            .unwrap();
+
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&body)
+            .contains(&format!("flood-channel is tracked in {DEFAULT_CHANNEL}")));
+    }
+
+    #[tokio::test]
+    async fn config_leaves_out_secrets() {
+        let (sender, _receiver) = channel(1);
+        let state = request_state(sender);
+        let request = Request::get("/config").body(Body::empty()).unwrap();
+
+        let response = router(state, 64).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let config: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(config.get("max_builders").is_some());
+        assert!(!String::from_utf8_lossy(&body).contains(REGISTRY_PASSWORD));
+    }
+
+    #[tokio::test]
+    async fn uploaded_local_package_gets_built() {
+        let (sender, mut receiver) = channel(8);
+        let state = request_state(sender);
+        let request = Request::post("/packages/hello-local/local")
+            .header("content-type", "application/x-tar")
+            .body(Body::from("PKGBUILD"))
+            .unwrap();
+
+        let response = router(state, 1024).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let package = "hello-local".to_string();
+        let info = state::package_info(&package).await.unwrap();
+        assert_eq!(info.source, PackageSource::Local);
+        assert_eq!(
+            state::read_local_source(&package).await.unwrap(),
+            b"PKGBUILD"
+        );
+        assert!(matches!(
+            receiver.recv().await,
+            Ok(Message::BuildPackage { package: built, priority: BuildPriority::Requested })
+                if built == package
+        ));
+    }
+
+    #[test]
+    fn filter_leaves_matching_packages() {
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        let mut status = Status {
+            packages: names(&["python-requests", "python-yaml", "paru"]),
+            dependencies: names(&["python-yaml"]),
+            evicted: Default::default(),
+            quarantined: names(&["paru"]),
+            out_of_date: Default::default(),
+            local: Default::default(),
+            worker_version: None,
+            docker_unavailable: false,
+            failing: Default::default(),
+            building: names(&["python-requests"]),
+            repository: Default::default(),
+            progress: Default::default(),
+            paused: false,
+            outside_build_window: false,
+        };
+
+        filter_status(&mut status, &glob::Pattern::new("python-*").unwrap());
+
+        assert_eq!(status.packages, names(&["python-requests", "python-yaml"]));
+        assert_eq!(status.dependencies, names(&["python-yaml"]));
+        assert!(status.quarantined.is_empty());
+        let counts = StatusCounts::from(&status);
+        assert_eq!((counts.packages, counts.building), (2, 1));
+    }
+
+    #[tokio::test]
+    async fn snapshot_contains_the_repository() {
+        let (sender, _receiver) = channel(1);
+        let file = TEST_ENVIRONMENT
+            .repo_dir
+            .join("snapshot-1.0-1-any.pkg.tar.zst");
+        std::fs::write(&file, "package").unwrap();
+        let request = Request::get("/snapshot").body(Body::empty()).unwrap();
+
+        let response = router(request_state(sender), 64)
+            .oneshot(request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let archive = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let mut tar = std::process::Command::new("tar")
+            .arg("-tf")
+            .arg("-")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        std::io::Write::write_all(&mut tar.stdin.take().unwrap(), &archive).unwrap();
+        let listing = tar.wait_with_output().unwrap();
+        assert!(String::from_utf8_lossy(&listing.stdout).contains("snapshot-1.0-1-any.pkg.tar.zst"));
+    }
+
+    /// Adds a package, uploads its artifacts like a worker would and checks that it ends up in
+    /// the repository. Docker is left out, the test takes the place of the worker.
+    #[tokio::test]
+    async fn added_package_ends_up_in_the_repository() {
+        let (sender, receiver) = channel(64);
+        let state = request_state(sender.clone());
+        let mut stop_token = StopToken::new();
+        let repository = tokio::spawn(repository::start(
+            sender.clone(),
+            receiver.resubscribe(),
+            stop_token.child(),
+        ));
+        let scheduler = tokio::spawn(scheduler::start(
+            sender.clone(),
+            receiver.resubscribe(),
+            stop_token.child(),
+        ));
+        drop(receiver);
+        let router = router(state, usize::MAX);
+        let package = "paru".to_string();
+
+        let response = router
+            .clone()
+            .oneshot(add_request(r#""paru""#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        wait_until(|| state::is_package_tracked(&package)).await;
+
+        let file = "paru-2.0.4-1-x86_64.pkg.tar.zst";
+        let response = router
+            .clone()
+            .oneshot(upload_request(&package, file))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        wait_until(|| async { !state::get_files(&package).await.is_empty() }).await;
+
+        let request = Request::get("/status").body(Body::empty()).unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        let status: Status =
+            serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap())
+                .unwrap();
+        assert!(status.packages.contains("paru"));
+        assert!(repository_files().contains(file));
+
+        stop_token.trigger_stop();
+        let _ = tokio::join!(repository, scheduler);
+    }
+
+    /// Floods a small channel after every request, so the scheduler and the repository miss the
+    /// messages about the added packages and their uploads.
+    #[tokio::test]
+    async fn missed_messages_lose_nothing() {
+        let (sender, receiver) = channel(2);
+        let state = request_state(sender.clone());
+        let mut stop_token = StopToken::new();
+        let repository = tokio::spawn(repository::start(
+            sender.clone(),
+            receiver.resubscribe(),
+            stop_token.child(),
+        ));
+        let scheduler = tokio::spawn(scheduler::start(
+            sender.clone(),
+            receiver.resubscribe(),
+            stop_token.child(),
+        ));
+        drop(receiver);
+        let router = router(state, usize::MAX);
+        let flood = || {
+            for _ in 0..4 {
+                let _ = sender.send(Message::BuildSuccess("flood".to_string()));
+            }
+        };
+        let packages: Vec<String> = (0..6).map(|n| format!("flood-{n}")).collect();
+
+        for package in &packages {
+            let request = add_request(&format!("\"{package}\""));
+            let response = router.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            flood();
+        }
+        for package in &packages {
+            wait_until(|| state::is_package_tracked(package)).await;
+        }
+
+        for package in &packages {
+            let file = format!("{package}-1.0-1-any.pkg.tar.zst");
+            let response = router
+                .clone()
+                .oneshot(upload_request(package, &file))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            flood();
+        }
+        for package in &packages {
+            wait_until(|| async { !state::get_files(package).await.is_empty() }).await;
+        }
+        let files = repository_files();
+        for package in &packages {
+            assert!(files.contains(&format!("{package}-1.0-1-any.pkg.tar.zst")));
+        }
+
+        stop_token.trigger_stop();
+        let _ = tokio::join!(repository, scheduler);
+    }
+}
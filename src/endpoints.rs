@@ -3,6 +3,9 @@ pub struct Endpoints {
     pub address: String,
     pub port: u16,
     pub https: bool,
+    /// The path the coordinator is served under, for example behind a reverse proxy. Empty if
+    /// it is served from the root
+    pub base_path: String,
 }
 
 impl Endpoints {
@@ -11,6 +14,36 @@ impl Endpoints {
         self.url("artifacts")
     }
 
+    #[must_use]
+    pub fn build_failure(&self) -> String {
+        self.url("build-failure")
+    }
+
+    #[must_use]
+    pub fn build_progress(&self) -> String {
+        self.url("build-progress")
+    }
+
+    #[must_use]
+    pub fn reset_failures(&self) -> String {
+        self.url("failures/reset")
+    }
+
+    #[must_use]
+    pub fn check_updates(&self) -> String {
+        self.url("check-updates")
+    }
+
+    #[must_use]
+    pub fn pause(&self) -> String {
+        self.url("pause")
+    }
+
+    #[must_use]
+    pub fn resume(&self) -> String {
+        self.url("resume")
+    }
+
     #[must_use]
     pub fn add_packages(&self) -> String {
         self.url("packages/add")
@@ -21,13 +54,59 @@ impl Endpoints {
         self.url("packages/remove")
     }
 
+    #[must_use]
+    pub fn rebuild_packages(&self) -> String {
+        self.url("packages/rebuild")
+    }
+
+    #[must_use]
+    pub fn package(&self, package: &str) -> String {
+        self.url(&format!("packages/{package}/details"))
+    }
+
+    #[must_use]
+    pub fn package_overrides(&self, package: &str) -> String {
+        self.url(&format!("packages/{package}/overrides"))
+    }
+
+    #[must_use]
+    pub fn local_package(&self, package: &str) -> String {
+        self.url(&format!("packages/{package}/local"))
+    }
+
+    #[must_use]
+    pub fn promote_package(&self, package: &str) -> String {
+        self.url(&format!("packages/{package}/promote"))
+    }
+
+    #[must_use]
+    pub fn dependency_closure(&self, package: &str) -> String {
+        self.url(&format!("packages/{package}/closure"))
+    }
+
+    #[must_use]
+    pub fn reverse_dependencies(&self, package: &str) -> String {
+        self.url(&format!("packages/{package}/rdeps"))
+    }
+
     #[must_use]
     pub fn status(&self) -> String {
         self.url("status")
     }
 
+    #[must_use]
+    pub fn config(&self) -> String {
+        self.url("config")
+    }
+
     fn base(&self) -> String {
-        format!("{}{}:{}/", self.protocol(), self.address, self.port)
+        let base_path = self.base_path.trim_matches('/');
+        let mut base = format!("{}{}:{}/", self.protocol(), self.address, self.port);
+        if !base_path.is_empty() {
+            base.push_str(base_path);
+            base.push('/');
+        }
+        base
     }
 
     fn url(&self, path: &str) -> String {
@@ -51,6 +130,7 @@ impl Default for Endpoints {
             port: 3200,
             address: String::new(),
             https: true,
+            base_path: String::new(),
         }
     }
 }
@@ -1,13 +1,15 @@
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt::Display;
 use std::str::FromStr;
 use itertools::Itertools;
-use tracing::info;
+use tracing::{info, warn};
 
 pub mod endpoints;
 
-const VERSION: &str = env!("APP_VERSION");
+pub const VERSION: &str = env!("APP_VERSION");
+/// Header the worker sends its version in.
+pub const WORKER_VERSION_HEADER: &str = "worker-version";
 
 pub fn abort_if_not_in_docker() {
     if !std::fs::exists("/.dockerenv").unwrap_or(false) {
@@ -19,25 +21,339 @@ pub fn abort_if_not_in_docker() {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct AddPackages {
     pub packages: HashSet<String>,
+    /// The channel the packages get added to. The coordinator's default one if not set
+    #[serde(default)]
+    pub channel: Option<String>,
+    /// Looks up the dependencies in the AUR and tracks them as well. Without it the packages are
+    /// built right away, relying on the builder image to provide their dependencies
+    #[serde(default = "enabled_by_default")]
+    pub resolve_deps: bool,
+    /// Queues a build of the packages right away. Without it they are only tracked, and get built
+    /// by the next scheduled update check
+    #[serde(default = "enabled_by_default")]
+    pub build_immediately: bool,
+}
+
+fn enabled_by_default() -> bool {
+    true
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct AddPackagesResponse {
-    pub added: HashSet<String>,
-    pub already_tracked: HashSet<String>,
-    pub not_found: HashSet<String>,
+    pub added: BTreeSet<String>,
+    pub already_tracked: BTreeSet<String>,
+    pub not_found: BTreeSet<String>,
+    /// Dependencies that got queued along with the added packages
+    #[serde(default)]
+    pub dependencies: BTreeSet<String>,
+    /// Packages with similar names for the ones that were not found
+    #[serde(default)]
+    pub suggestions: BTreeMap<String, Vec<String>>,
+    /// Packages that were not added, as they would exceed the coordinator's limit on tracked
+    /// packages
+    #[serde(default)]
+    pub rejected_limit: BTreeSet<String>,
 }
 
+/// The artifacts are uploaded as a multipart form. This is its first part, named
+/// [`ARTIFACTS_METADATA_FIELD`], which is followed by a [`ARTIFACTS_FILE_FIELD`] part per file.
 #[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct Artifacts {
+pub struct ArtifactsMetadata {
     pub package_name: String,
     pub build_time: i64,
-    pub files: HashMap<String, Vec<u8>>,
+    /// The commit of the PKGBUILD repository, if the worker could determine it
+    #[serde(default)]
+    pub commit: Option<String>,
+    /// The SHA-256 of every file, by file name
+    #[serde(default)]
+    pub checksums: HashMap<String, String>,
+    /// The packages the PKGBUILD builds according to its `.SRCINFO`, including the debug package.
+    /// Empty if the worker could not read them
+    #[serde(default)]
+    pub pkgnames: Vec<String>,
+}
+
+pub const ARTIFACTS_METADATA_FIELD: &str = "metadata";
+pub const ARTIFACTS_FILE_FIELD: &str = "file";
+
+/// Sent by a worker whenever its build moves on to the next stage.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BuildProgressReport {
+    pub package_name: String,
+    pub stage: BuildStage,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BuildStage {
+    /// Synchronizing the package databases
+    UpdatingDatabases,
+    /// Fetching the PKGBUILD
+    Cloning,
+    /// Verifying the sources and building the package
+    Building,
+    /// Sending the artifacts to the coordinator
+    Uploading,
+}
+
+impl Display for BuildStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildStage::UpdatingDatabases => write!(f, "updating databases"),
+            BuildStage::Cloning => write!(f, "cloning"),
+            BuildStage::Building => write!(f, "building"),
+            BuildStage::Uploading => write!(f, "uploading"),
+        }
+    }
+}
+
+/// The stage a running build is in.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BuildProgress {
+    pub stage: BuildStage,
+    /// When the build reported its first stage
+    pub started: i64,
+    /// When the build entered the stage
+    pub since: i64,
+}
+
+/// Sent by a worker when a command of its build failed.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BuildFailureReport {
+    pub package_name: String,
+    pub failure: BuildFailure,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BuildFailure {
+    pub time: i64,
+    /// The command line that failed
+    pub command: String,
+    /// Missing if the command was terminated by a signal
+    pub exit_code: Option<i32>,
+    /// The last lines the command printed
+    pub output: Vec<String>,
+    #[serde(default)]
+    pub reason: FailureReason,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FailureReason {
+    /// Fetching, building or packaging failed
+    #[default]
+    Build,
+    /// The sources did not match the checksums of the PKGBUILD
+    SourceVerification,
+    /// The PKGBUILD or its sources could not be fetched, as the package was renamed, removed or
+    /// a server is down
+    Fetch,
+}
+
+impl Display for FailureReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FailureReason::Build => write!(f, "build failed"),
+            FailureReason::SourceVerification => write!(f, "source verification failed"),
+            FailureReason::Fetch => write!(f, "could not fetch the package or its sources"),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Status {
+    pub packages: BTreeSet<String>,
+    #[serde(default)]
+    pub dependencies: BTreeSet<String>,
+    /// Packages whose build got evicted to stay below the repository's maximum size
+    #[serde(default)]
+    pub evicted: BTreeSet<String>,
+    /// Packages that failed all their retries and don't get built anymore
+    #[serde(default)]
+    pub quarantined: BTreeSet<String>,
+    /// Packages flagged as out of date in the AUR
+    #[serde(default)]
+    pub out_of_date: BTreeSet<String>,
+    /// Packages built from uploaded sources instead of the AUR
+    #[serde(default)]
+    pub local: BTreeSet<String>,
+    /// Version of the worker that sent the last artifacts
+    #[serde(default)]
+    pub worker_version: Option<String>,
+    /// Set whilst the coordinator can't reach Docker, so nothing gets built
+    #[serde(default)]
+    pub docker_unavailable: bool,
+    /// Packages whose last build failed, but that get retried
+    #[serde(default)]
+    pub failing: BTreeSet<String>,
+    /// Packages that are being built right now
+    #[serde(default)]
+    pub building: BTreeSet<String>,
+    #[serde(default)]
+    pub repository: RepositoryStats,
+    /// The stage of each running build that reported one
+    #[serde(default)]
+    pub progress: BTreeMap<String, BuildProgress>,
+    /// Set whilst no new builds get started, running ones still finish
+    #[serde(default)]
+    pub paused: bool,
+    /// Set whilst new builds wait for the configured build window to open
+    #[serde(default)]
+    pub outside_build_window: bool,
+}
+
+/// The sizes of the sets of a [`Status`], sent instead of it when only the counts are asked for.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StatusCounts {
+    pub packages: usize,
+    pub dependencies: usize,
+    pub quarantined: usize,
+    pub out_of_date: usize,
+    pub failing: usize,
+    pub building: usize,
+}
+
+impl From<&Status> for StatusCounts {
+    fn from(status: &Status) -> Self {
+        Self {
+            packages: status.packages.len(),
+            dependencies: status.dependencies.len(),
+            quarantined: status.quarantined.len(),
+            out_of_date: status.out_of_date.len(),
+            failing: status.failing.len(),
+            building: status.building.len(),
+        }
+    }
+}
+
+/// How much space the repositories take up.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RepositoryStats {
+    /// Size of all files in the repository directory in bytes
+    pub size: u64,
+    /// Number of package files across all channels
+    pub package_files: usize,
+    /// Free space on the filesystem of the repository directory in bytes, if it could be read
+    pub free_space: Option<u64>,
+}
+
+/// Where the PKGBUILD of a package comes from.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PackageSource {
+    #[default]
+    Aur,
+    /// Uploaded as a tar archive, so it isn't checked for updates
+    Local,
+}
+
+impl Display for PackageSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PackageSource::Aur => write!(f, "AUR"),
+            PackageSource::Local => write!(f, "local"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PackageDetails {
+    pub name: String,
+    pub is_dependency: bool,
+    pub dependencies: BTreeSet<String>,
+    pub build: Option<BuildDetails>,
+    pub overrides: PackageOverrides,
+    #[serde(default)]
+    pub last_failure: Option<BuildFailure>,
+    #[serde(default)]
+    pub quarantined_since: Option<i64>,
+    /// When the package got flagged as out of date in the AUR
+    #[serde(default)]
+    pub out_of_date: Option<i64>,
+    #[serde(default)]
+    pub channel: String,
+    /// Builds that got promoted to other channels, by channel
+    #[serde(default)]
+    pub promoted: BTreeMap<String, BuildDetails>,
+    #[serde(default)]
+    pub source: PackageSource,
+}
+
+/// Copies the current build of a package from one channel into another.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PromotePackage {
+    pub from: String,
+    pub to: String,
+}
+
+/// Build settings of a single package that take precedence over the coordinator's config.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PackageOverrides {
+    /// Memory limit of the build container in bytes
+    pub memory_limit: Option<i64>,
+    /// Replaces the configured `EXTRA_BUILD_ARGS` when not empty
+    pub extra_build_args: Vec<String>,
+    /// Lines written to the input of the build, answering its prompts in order
+    #[serde(default)]
+    pub answers: Vec<String>,
+    /// Lines appended to the PKGBUILD before building, which can override its variables
+    #[serde(default)]
+    pub pkgbuild_additions: Vec<String>,
+}
+
+/// Changes the [`PackageOverrides`] of a package. Settings that are left out keep their value.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct OverridesUpdate {
+    /// Clears all overrides before applying the ones that are set
+    #[serde(default)]
+    pub reset: bool,
+    #[serde(default)]
+    pub memory_limit: Option<i64>,
+    #[serde(default)]
+    pub extra_build_args: Option<Vec<String>>,
+    #[serde(default)]
+    pub answers: Option<Vec<String>>,
+    #[serde(default)]
+    pub pkgbuild_additions: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BuildDetails {
+    pub time: i64,
+    pub files: Vec<String>,
+    /// The commit of the PKGBUILD repository the package was built from
+    #[serde(default)]
+    pub commit: Option<String>,
+    /// The SHA-256 of every file, by file name
+    #[serde(default)]
+    pub checksums: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReverseDependencies {
+    pub name: String,
+    pub required_by: BTreeSet<String>,
+}
+
+/// Every package a package depends on, directly or through other packages.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DependencyClosure {
+    pub name: String,
+    /// Ordered so that the dependencies of a package come before it
+    pub closure: Vec<String>,
+    /// The direct dependencies of the package and of every package in the closure
+    pub dependencies: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RebuildPackages {
     pub packages: HashSet<String>,
+    /// Removes the current builds from the repository before building the packages again
+    #[serde(default)]
+    pub clean: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RebuildPackagesResponse {
+    pub rebuilding: BTreeSet<String>,
+    pub not_tracked: BTreeSet<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -47,30 +363,66 @@ pub struct RemovePackages {
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct RemovePackagesResponse {
-    pub removed: HashSet<String>,
-    pub not_tracked: HashSet<String>,
+    pub removed: BTreeSet<String>,
+    pub not_tracked: BTreeSet<String>,
+}
+
+/// Body of every error response sent by the coordinator.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ErrorResponse {
+    pub error: String,
 }
 
 pub fn env_or<T>(var: &str, or: T) -> T
 where
     T: FromStr,
 {
-    std::env::var(var)
-        .ok()
-        .and_then(|val| val.parse::<T>().ok())
-        .unwrap_or(or)
+    env_or_none(var).unwrap_or(or)
+}
+
+/// Reads and parses the variable. A value that doesn't parse is treated as unset, but warned
+/// about, so a typo doesn't silently leave the default in place.
+pub fn env_or_none<T>(var: &str) -> Option<T>
+where
+    T: FromStr,
+{
+    let value = std::env::var(var).ok()?;
+    let parsed = value.parse::<T>().ok();
+    if parsed.is_none() {
+        warn!("Ignoring {var}, as '{value}' is not a valid value for it");
+    }
+    parsed
+}
+
+/// Parses a comma separated list of glob patterns, like the one in `ARTIFACT_EXCLUDE`.
+pub fn parse_patterns(list: &str) -> Result<Vec<glob::Pattern>, String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|pattern| !pattern.is_empty())
+        .map(|pattern| {
+            glob::Pattern::new(pattern).map_err(|err| format!("'{pattern}' is invalid: {err}"))
+        })
+        .collect()
+}
+
+/// Formats a SHA-256 digest the way `sha256sum` does.
+pub fn checksum_hex(digest: [u8; 32]) -> String {
+    digest.iter().map(|byte| format!("{byte:02x}")).collect()
 }
 
 pub fn print_version() {
     info!("Version built from {VERSION}");
 }
 
+/// Joins the items into a sentence. They are sorted first, so the output doesn't depend on the
+/// order of the collection they come from.
 pub fn combine_for_display<S, I>(list: S) -> String
 where
     S: IntoIterator<Item = I>,
     I: AsRef<str> + Display,
 {
-    let list = list.into_iter().collect::<Vec<I>>();
+    let mut list = list.into_iter().collect::<Vec<I>>();
+    list.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
     match list.len() {
         0 => String::new(),
         1 => list[0].to_string(),
@@ -82,4 +434,97 @@ where
             format!("{all_but_last} and {last_part}")
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_order_is_stable() {
+        let packages: HashSet<&str> = ["yay", "paru", "aurutils", "pikaur"].into_iter().collect();
+        assert_eq!(
+            combine_for_display(&packages),
+            "aurutils, paru, pikaur and yay"
+        );
+        assert_eq!(combine_for_display(["b", "a"]), "a and b");
+    }
+
+    #[test]
+    fn patterns_match_debug_packages() {
+        let patterns = parse_patterns("*-debug-*, ").unwrap();
+        let excluded = |file: &str| patterns.iter().any(|pattern| pattern.matches(file));
+
+        assert!(excluded("paru-debug-2.0.4-1-x86_64.pkg.tar.zst"));
+        assert!(!excluded("paru-2.0.4-1-x86_64.pkg.tar.zst"));
+        assert!(parse_patterns("[debug").is_err());
+    }
+
+    #[test]
+    fn dependencies_get_resolved_unless_disabled() {
+        let add: AddPackages = serde_json::from_str(r#"{"packages": ["paru"]}"#).unwrap();
+        assert!(add.resolve_deps);
+
+        let add: AddPackages =
+            serde_json::from_str(r#"{"packages": ["paru"], "resolve_deps": false}"#).unwrap();
+        assert!(!add.resolve_deps);
+    }
+
+    #[test]
+    fn packages_get_built_right_away_unless_disabled() {
+        let add: AddPackages = serde_json::from_str(r#"{"packages": ["paru"]}"#).unwrap();
+        assert!(add.build_immediately);
+
+        let add: AddPackages =
+            serde_json::from_str(r#"{"packages": ["paru"], "build_immediately": false}"#).unwrap();
+        assert!(!add.build_immediately);
+    }
+
+    #[test]
+    fn responses_serialize_in_a_stable_order() {
+        let response = RemovePackagesResponse {
+            removed: ["zsh-git", "alacritty-git", "neovim-git"]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            not_tracked: BTreeSet::new(),
+        };
+        let json = serde_json::to_string(&response).unwrap();
+        assert_eq!(
+            json,
+            r#"{"removed":["alacritty-git","neovim-git","zsh-git"],"not_tracked":[]}"#
+        );
+
+        let response = reserialize::<AddPackagesResponse>(
+            r#"{"added": [], "already_tracked": [], "not_found": ["zsh-gti", "alacrity"],
+                "suggestions": {"zsh-gti": ["zsh-git"], "alacrity": ["alacritty"]}}"#,
+        );
+        assert!(response.contains(r#"{"alacrity":["alacritty"],"zsh-gti":["zsh-git"]}"#));
+
+        let status = reserialize::<Status>(
+            r#"{"packages": [], "progress": {
+                "zsh-git": {"stage": "Building", "started": 1, "since": 2},
+                "alacritty-git": {"stage": "Cloning", "started": 1, "since": 1}}}"#,
+        );
+        assert!(status.contains(r#""progress":{"alacritty-git":"#));
+
+        let details = reserialize::<PackageDetails>(
+            r#"{"name": "paru", "is_dependency": false, "dependencies": [], "build": null,
+                "overrides": {"extra_build_args": []}, "promoted": {
+                    "testing": {"time": 1, "files": [], "checksums": {"b": "2", "a": "1"}},
+                    "stable": {"time": 1, "files": [], "checksums": {}}}}"#,
+        );
+        assert!(details.contains(r#""promoted":{"stable":"#));
+        assert!(details.contains(r#""checksums":{"a":"1","b":"2"}"#));
+
+        let closure = reserialize::<DependencyClosure>(
+            r#"{"name": "paru", "closure": [], "dependencies": {"paru": ["rust"], "rust": []}}"#,
+        );
+        assert!(closure.contains(r#""dependencies":{"paru":["rust"],"rust":[]}"#));
+    }
+
+    /// Reads the JSON and writes it back out, with the maps in the order they serialize in.
+    fn reserialize<T: Serialize + for<'de> Deserialize<'de>>(json: &str) -> String {
+        serde_json::to_string(&serde_json::from_str::<T>(json).unwrap()).unwrap()
+    }
+}
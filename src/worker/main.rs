@@ -1,13 +1,36 @@
 use coordinator::endpoints::Endpoints;
-use coordinator::{abort_if_not_in_docker, print_version, Artifacts};
+use coordinator::{
+    abort_if_not_in_docker, checksum_hex, parse_patterns, print_version, ArtifactsMetadata,
+    BuildFailure, BuildFailureReport, BuildProgressReport, BuildStage, FailureReason,
+    ARTIFACTS_FILE_FIELD, ARTIFACTS_METADATA_FIELD, VERSION, WORKER_VERSION_HEADER,
+};
+use openssl::sha::Sha256;
 use reqwest::header::{HeaderMap, HeaderValue};
-use std::collections::HashMap;
+use reqwest::multipart::{Form, Part};
+use reqwest::StatusCode;
+use std::collections::{HashMap, VecDeque};
 use std::fs::{create_dir_all, exists, read_to_string, remove_dir_all};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
 use thiserror::Error;
 use time::OffsetDateTime;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::process::Command;
+use tokio::select;
+use tokio_util::io::ReaderStream;
 use tracing::{error, info, log, Level};
 
+/// How many lines of a failed command's output are sent to the coordinator.
+const OUTPUT_LINES: usize = 50;
+const DEFAULT_UPLOAD_RETRIES: u32 = 5;
+/// Longest delay between two upload attempts, in seconds.
+const MAX_UPLOAD_DELAY: u64 = 60;
+/// Where the coordinator puts the sources of local packages.
+const LOCAL_SOURCE_DIR: &str = "/home/worker/local";
+/// The gateway of Docker's default bridge network, used if the coordinator doesn't pass its address.
+const DEFAULT_COORDINATOR_HOST: &str = "172.17.0.1";
+
 #[tokio::main]
 async fn main() -> Result<(), AppError> {
     abort_if_not_in_docker();
@@ -20,14 +43,12 @@ async fn main() -> Result<(), AppError> {
     let hostname = read_to_string("/etc/hostname")?.replace('\n', "");
     info!("Hostname: {hostname}");
     headers.insert("hostname", HeaderValue::from_str(&hostname)?);
+    headers.insert(WORKER_VERSION_HEADER, HeaderValue::from_static(VERSION));
     let client = reqwest::Client::builder()
         .default_headers(headers)
         .build()?;
-    let endpoints = Endpoints {
-        address: "172.17.0.1".to_string(),
-        https: false,
-        ..Default::default()
-    };
+    let endpoints = coordinator_endpoints();
+    info!("Coordinator: {}:{}", endpoints.address, endpoints.port);
 
     let Ok(package) = std::env::var("PACKAGE") else {
         error!("Failed to read environment variable 'PACKAGE'");
@@ -35,43 +56,297 @@ async fn main() -> Result<(), AppError> {
     };
 
     log::info!("Building {}", package);
-    let artifacts = build_pkg(package).await?;
+    let (metadata, files) = match build_pkg(&client, &endpoints, package.clone()).await {
+        Err(AppError::ProcessFailed(failure)) => {
+            report_failure(&client, &endpoints, package, &failure).await;
+            return Err(AppError::ProcessFailed(failure));
+        }
+        result => result?,
+    };
+
+    report_progress(
+        &client,
+        &endpoints,
+        &metadata.package_name,
+        BuildStage::Uploading,
+    )
+    .await;
+    upload_artifacts(&client, &endpoints, &metadata, &files).await
+}
+
+/// Where the coordinator is reached, as passed by it through `COORDINATOR_HOST` and
+/// `COORDINATOR_PORT`. It is always plain HTTP, as the workers don't know its certificate.
+fn coordinator_endpoints() -> Endpoints {
+    let default = Endpoints::default();
+    Endpoints {
+        address: std::env::var("COORDINATOR_HOST")
+            .ok()
+            .filter(|host| !host.is_empty())
+            .unwrap_or_else(|| DEFAULT_COORDINATOR_HOST.to_string()),
+        port: std::env::var("COORDINATOR_PORT")
+            .ok()
+            .and_then(|port| port.parse().ok())
+            .unwrap_or(default.port),
+        https: false,
+        ..default
+    }
+}
+
+/// Uploads the artifacts, retrying with a growing delay whilst the coordinator can't be reached,
+/// fails internally or asks to slow down. The number of retries is set through `UPLOAD_RETRIES`.
+/// Any other rejection fails right away, as the same upload would be rejected again.
+async fn upload_artifacts(
+    client: &reqwest::Client,
+    endpoints: &Endpoints,
+    metadata: &ArtifactsMetadata,
+    files: &[(String, PathBuf)],
+) -> Result<(), AppError> {
+    let retries: u32 = std::env::var("UPLOAD_RETRIES")
+        .ok()
+        .and_then(|retries| retries.parse().ok())
+        .unwrap_or(DEFAULT_UPLOAD_RETRIES);
+
+    let mut attempt = 0;
+    loop {
+        // The form streams the files, so it has to be built again for every attempt.
+        let result = client
+            .post(endpoints.artifacts())
+            .multipart(artifacts_form(metadata, files).await?)
+            .send()
+            .await;
+        let error = match result {
+            Ok(response) if response.status().is_success() => {
+                log::info!("Sent off artifacts. Got back a {}", response.status());
+                return Ok(());
+            }
+            Ok(response)
+                if response.status().is_client_error()
+                    && response.status() != StatusCode::TOO_MANY_REQUESTS =>
+            {
+                let status = response.status();
+                let reason = response.text().await.unwrap_or_default();
+                return Err(AppError::Upload(format!(
+                    "the coordinator rejected them with {status}: {reason}"
+                )));
+            }
+            // Retrying gives the operator or the coordinator a chance to free up space.
+            Ok(response) if response.status() == StatusCode::INSUFFICIENT_STORAGE => {
+                "the coordinator ran out of disk space".to_string()
+            }
+            Ok(response) => format!("the coordinator answered with {}", response.status()),
+            Err(err) => err.to_string(),
+        };
+        if attempt >= retries {
+            return Err(AppError::Upload(error));
+        }
+
+        attempt += 1;
+        let delay = Duration::from_secs(2u64.pow(attempt).min(MAX_UPLOAD_DELAY));
+        log::warn!(
+            "Failed to upload the artifacts, {error}. Retrying in {delay:?} ({attempt}/{retries})"
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
 
-    let response = client
-        .post(endpoints.artifacts())
-        .json(&artifacts)
+/// Tells the coordinator which stage the build is in. Only logged if that fails, as the build
+/// itself doesn't depend on it.
+async fn report_progress(
+    client: &reqwest::Client,
+    endpoints: &Endpoints,
+    package_name: &str,
+    stage: BuildStage,
+) {
+    let report = BuildProgressReport {
+        package_name: package_name.to_string(),
+        stage,
+    };
+    match client
+        .post(endpoints.build_progress())
+        .json(&report)
         .send()
-        .await?;
+        .await
+    {
+        Ok(response) if !response.status().is_success() => {
+            log::warn!(
+                "Reporting the build progress got back a {}",
+                response.status()
+            );
+        }
+        Ok(_) => (),
+        Err(err) => log::warn!("Failed to report the build progress: {err}"),
+    }
+}
 
-    log::info!("Sent off artifacts. Got back a {}", response.status());
-    Ok(())
+async fn report_failure(
+    client: &reqwest::Client,
+    endpoints: &Endpoints,
+    package_name: String,
+    failure: &BuildFailure,
+) {
+    let report = BuildFailureReport {
+        package_name,
+        failure: failure.clone(),
+    };
+    match client
+        .post(endpoints.build_failure())
+        .json(&report)
+        .send()
+        .await
+    {
+        Ok(response) => log::info!("Sent off failure report. Got back a {}", response.status()),
+        Err(err) => error!("Failed to send failure report: {err}"),
+    }
 }
 
-async fn build_pkg(package_name: String) -> Result<Artifacts, AppError> {
-    if exists("/home/worker/build")? {
-        remove_dir_all("/home/worker/build")?;
+/// Builds the upload of the artifacts. The files are streamed from disk rather than read into
+/// memory first.
+async fn artifacts_form(
+    metadata: &ArtifactsMetadata,
+    files: &[(String, PathBuf)],
+) -> Result<Form, AppError> {
+    let metadata = Part::text(serde_json::to_string(metadata)?).mime_str("application/json")?;
+    let mut form = Form::new().part(ARTIFACTS_METADATA_FIELD, metadata);
+    for (name, path) in files {
+        let file = tokio::fs::File::open(path).await?;
+        let length = file.metadata().await?.len();
+        let body = reqwest::Body::wrap_stream(ReaderStream::new(file));
+        form = form.part(
+            ARTIFACTS_FILE_FIELD,
+            Part::stream_with_length(body, length).file_name(name.clone()),
+        );
+    }
+    Ok(form)
+}
+
+/// Builds the package, returning the files that should be uploaded.
+async fn build_pkg(
+    client: &reqwest::Client,
+    endpoints: &Endpoints,
+    package_name: String,
+) -> Result<(ArtifactsMetadata, Vec<(String, PathBuf)>), AppError> {
+    // Incremental builds keep the directory, which the coordinator mounts as a volume.
+    let clean_build = std::env::var("CLEAN_BUILD").map_or(true, |clean| clean != "false");
+    if clean_build {
+        if exists("/home/worker/build")? {
+            remove_dir_all("/home/worker/build")?;
+        }
+        create_dir_all("/home/worker/build")?;
+    } else {
+        remove_stale_files(&package_name)?;
+        restore_pkgbuild(&package_name).await?;
+    }
+
+    if std::env::var("CCACHE").is_ok_and(|ccache| ccache == "true") {
+        enable_ccache()?;
     }
-    create_dir_all("/home/worker/build")?;
 
     let build_time = OffsetDateTime::now_utc().unix_timestamp();
 
+    report_progress(
+        client,
+        endpoints,
+        &package_name,
+        BuildStage::UpdatingDatabases,
+    )
+    .await;
     run_command("paru", &["-Sy"]).await?;
-    run_command("paru", &["-G", &package_name]).await?;
-    run_command(
+    let aur_url = std::env::var("AUR_GIT_BASE")
+        .map(|url| format!("--aururl={url}"))
+        .ok();
+    let package_dir = format!("/home/worker/build/{package_name}");
+    let local_source = std::env::var("LOCAL_SOURCE").is_ok_and(|local| local == "true");
+    report_progress(client, endpoints, &package_name, BuildStage::Cloning).await;
+    if local_source {
+        // Copied rather than built in place, so the files belong to the worker.
+        create_dir_all(&package_dir)?;
+        run_command(
+            "cp",
+            &["-r", &format!("{LOCAL_SOURCE_DIR}/."), &package_dir],
+        )
+        .await?;
+    } else {
+        let mut fetch_args = vec!["-G"];
+        fetch_args.extend(aur_url.as_deref());
+        fetch_args.push(&package_name);
+        run_command("paru", &fetch_args)
+            .await
+            .map_err(failed_because(FailureReason::Fetch))?;
+        // paru doesn't always fail for packages it can't find, so the directory is checked too.
+        if !exists(&package_dir)? {
+            return Err(AppError::ProcessFailed(BuildFailure {
+                time: OffsetDateTime::now_utc().unix_timestamp(),
+                command: format!("paru {}", fetch_args.join(" ")),
+                exit_code: None,
+                output: vec![format!(
+                    "{package_name} was not fetched. It might have been renamed or removed from the AUR"
+                )],
+                reason: FailureReason::Fetch,
+            }));
+        }
+    }
+
+    if let Ok(additions) = std::env::var("PKGBUILD_ADDITIONS") {
+        append_to_pkgbuild(&package_dir, &additions)?;
+    }
+
+    // Downloaded and checked on their own, so unreachable or mismatching sources can be told apart
+    // from broken builds. PGP signatures are left to the build, as paru imports the missing keys
+    // first.
+    report_progress(client, endpoints, &package_name, BuildStage::Building).await;
+    run_command_in(&package_dir, "makepkg", &["--verifysource", "--skipinteg"])
+        .await
+        .map_err(failed_because(FailureReason::Fetch))?;
+    run_command_in(
+        &package_dir,
+        "makepkg",
+        &["--verifysource", "--skippgpcheck"],
+    )
+    .await
+    .map_err(failed_because(FailureReason::SourceVerification))?;
+
+    let commit = if local_source {
+        None
+    } else {
+        read_commit(&package_dir).await
+    };
+    let pkgnames = read_pkgnames(&package_dir).await;
+
+    // Extra arguments are passed on as is, so invalid ones make the build fail.
+    let extra_build_args = std::env::var("EXTRA_BUILD_ARGS").unwrap_or_default();
+    let mut build_args = vec![
+        "-B",
+        "--nouseask",
+        "--skipreview",
+        "--noupgrademenu",
+        "--failfast",
+    ];
+    build_args.extend(aur_url.as_deref());
+    build_args.extend(extra_build_args.split_whitespace());
+    build_args.push(&package_name);
+    // Answers for the prompts of the build, one per line.
+    let answers = std::env::var("BUILD_ANSWERS")
+        .ok()
+        .map(|answers| answers + "\n");
+    run_command_with_input(
+        "/home/worker/build",
         "paru",
-        &[
-            "-B",
-            "--nouseask",
-            "--skipreview",
-            "--noupgrademenu",
-            "--failfast",
-            &package_name,
-        ],
+        &build_args,
+        answers.as_deref(),
     )
     .await?;
 
+    // The coordinator refuses to start with invalid patterns, so they are only logged here.
+    let exclude = std::env::var("ARTIFACT_EXCLUDE")
+        .ok()
+        .and_then(|exclude| {
+            parse_patterns(&exclude)
+                .inspect_err(|err| error!("{err}"))
+                .ok()
+        })
+        .unwrap_or_default();
     let mut dir = tokio::fs::read_dir(format!("/home/worker/build/{package_name}")).await?;
-    let mut files = HashMap::new();
+    let mut files = Vec::new();
     while let Some(entry) = dir.next_entry().await? {
         if entry.file_type().await?.is_file()
             && entry
@@ -80,38 +355,276 @@ async fn build_pkg(package_name: String) -> Result<Artifacts, AppError> {
                 .ends_with(".pkg.tar.zst")
         {
             let name = entry.file_name().to_string_lossy().to_string();
-            let data = tokio::fs::read(entry.path()).await?;
+            if exclude.iter().any(|pattern| pattern.matches(&name)) {
+                log::info!("Skipping excluded file: {name}");
+                continue;
+            }
 
             log::info!("File: {name}");
 
-            files.insert(name, data);
+            files.push((name, entry.path()));
         }
     }
 
-    Ok(Artifacts {
+    let mut checksums = HashMap::new();
+    for (name, path) in &files {
+        checksums.insert(name.clone(), checksum(path).await?);
+    }
+
+    let metadata = ArtifactsMetadata {
         package_name,
         build_time,
-        files,
-    })
+        commit,
+        checksums,
+        pkgnames,
+    };
+    Ok((metadata, files))
+}
+
+/// Gives the failure of a command the reason, so the coordinator can tell the steps of the build
+/// apart.
+fn failed_because(reason: FailureReason) -> impl FnOnce(AppError) -> AppError {
+    move |err| match err {
+        AppError::ProcessFailed(failure) => {
+            AppError::ProcessFailed(BuildFailure { reason, ..failure })
+        }
+        err => err,
+    }
+}
+
+/// Turns on ccache in makepkg. The cache itself is a volume the coordinator mounts.
+fn enable_ccache() -> Result<(), AppError> {
+    std::fs::write(
+        "/home/worker/.makepkg.conf",
+        "BUILDENV=(!distcc color ccache check !sign)\n",
+    )?;
+    log::info!("Building with ccache");
+    Ok(())
+}
+
+/// Appends the lines to the PKGBUILD. As it is sourced from top to bottom, variables set here
+/// replace the ones of the maintainer.
+fn append_to_pkgbuild(package_dir: &str, additions: &str) -> Result<(), AppError> {
+    let path = Path::new(package_dir).join("PKGBUILD");
+    let mut pkgbuild = read_to_string(&path)?;
+    if !pkgbuild.ends_with('\n') {
+        pkgbuild.push('\n');
+    }
+    pkgbuild.push_str("\n# Added by archie\n");
+    pkgbuild.push_str(additions);
+    pkgbuild.push('\n');
+    std::fs::write(path, pkgbuild)?;
+    log::info!("Appended to the PKGBUILD:\n{additions}");
+    Ok(())
+}
+
+/// Undoes the additions of the previous build, so paru can update the checkout. The sources of
+/// local packages are copied over anyway.
+async fn restore_pkgbuild(package_name: &str) -> Result<(), AppError> {
+    let package_dir = format!("/home/worker/build/{package_name}");
+    if !exists(Path::new(&package_dir).join(".git"))? {
+        return Ok(());
+    }
+    run_command_in(&package_dir, "git", &["checkout", "--", "PKGBUILD"]).await
+}
+
+/// Removes everything from the build directory but the package's own, and the packages of its
+/// previous build, so only the files of this build get uploaded.
+fn remove_stale_files(package_name: &str) -> Result<(), AppError> {
+    create_dir_all("/home/worker/build")?;
+    for entry in std::fs::read_dir("/home/worker/build")? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy() == package_name {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            remove_dir_all(entry.path())?;
+        } else {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+
+    let package_dir = Path::new("/home/worker/build").join(package_name);
+    if !exists(&package_dir)? {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(package_dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file()
+            && entry
+                .file_name()
+                .to_string_lossy()
+                .ends_with(".pkg.tar.zst")
+        {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
 }
 
+/// Computes the SHA-256 of a file without reading all of it into memory.
+async fn checksum(path: &Path) -> Result<String, AppError> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(checksum_hex(hasher.finish()));
+        }
+        hasher.update(&buffer[..read]);
+    }
+}
+
+/// Reads the commit paru checked out the PKGBUILD at. Only logged if that fails, as the build
+/// itself doesn't depend on it.
+async fn read_commit(package_dir: &str) -> Option<String> {
+    let output = Command::new("git")
+        .args(["-C", package_dir, "rev-parse", "HEAD"])
+        .output()
+        .await;
+    match output {
+        Ok(output) if output.status.success() => {
+            let commit = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            info!("Building from commit {commit}");
+            Some(commit)
+        }
+        Ok(output) => {
+            error!(
+                "Failed to read the commit of the PKGBUILD: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            None
+        }
+        Err(err) => {
+            error!("Failed to run git: {err}");
+            None
+        }
+    }
+}
+
+/// Reads the names of the packages the PKGBUILD builds, so the coordinator can tell them apart from
+/// packages it has no business building. makepkg names the debug package after the `pkgbase`.
+async fn read_pkgnames(package_dir: &str) -> Vec<String> {
+    let output = Command::new("makepkg")
+        .arg("--printsrcinfo")
+        .current_dir(package_dir)
+        .output()
+        .await;
+    match output {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| {
+                let (key, value) = line.trim().split_once(" = ")?;
+                match key {
+                    "pkgname" => Some(value.to_string()),
+                    "pkgbase" => Some(format!("{value}-debug")),
+                    _ => None,
+                }
+            })
+            .collect(),
+        Ok(output) => {
+            error!(
+                "Failed to read the package names of the PKGBUILD: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            Vec::new()
+        }
+        Err(err) => {
+            error!("Failed to run makepkg: {err}");
+            Vec::new()
+        }
+    }
+}
+
+/// Runs the command, passing its output through whilst keeping the last lines for the failure
+/// report.
 async fn run_command(app: &str, args: &[&str]) -> Result<(), AppError> {
-    let output = Command::new(app)
-        .current_dir("/home/worker/build")
+    run_command_in("/home/worker/build", app, args).await
+}
+
+async fn run_command_in(dir: &str, app: &str, args: &[&str]) -> Result<(), AppError> {
+    run_command_with_input(dir, app, args, None).await
+}
+
+/// Runs the command with the input written to its stdin. Without input, stdin is closed right
+/// away, so a prompt fails instead of waiting forever.
+async fn run_command_with_input(
+    dir: &str,
+    app: &str,
+    args: &[&str],
+    input: Option<&str>,
+) -> Result<(), AppError> {
+    let mut child = Command::new(app)
+        .current_dir(dir)
         .args(args)
-        .spawn()?
-        .wait_with_output()
-        .await
-        .expect("Failed to execute command");
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+        let input = input.to_string();
+        // Written alongside reading the output, as the command might not read all of it.
+        tokio::spawn(async move {
+            if let Err(err) = stdin.write_all(input.as_bytes()).await {
+                error!("Failed to write the answers: {err}");
+            }
+        });
+    }
 
-    if !output.status.success() {
+    let mut stdout = child.stdout.take().map(|out| BufReader::new(out).lines());
+    let mut stderr = child.stderr.take().map(|err| BufReader::new(err).lines());
+    let mut output = VecDeque::with_capacity(OUTPUT_LINES);
+    while stdout.is_some() || stderr.is_some() {
+        let line = select! {
+            Some(line) = next_line(&mut stdout) => {
+                line.inspect(|line| println!("{line}"))
+            }
+            Some(line) = next_line(&mut stderr) => {
+                line.inspect(|line| eprintln!("{line}"))
+            }
+            else => None,
+        };
+        if let Some(line) = line {
+            if output.len() == OUTPUT_LINES {
+                output.pop_front();
+            }
+            output.push_back(line);
+        }
+    }
+
+    let status = child.wait().await?;
+    if !status.success() {
         log::error!("Command {app} did not exit successfully");
-        return Err(AppError::ProcessFailed);
+        return Err(AppError::ProcessFailed(BuildFailure {
+            time: OffsetDateTime::now_utc().unix_timestamp(),
+            command: format!("{app} {}", args.join(" ")),
+            exit_code: status.code(),
+            output: output.into(),
+            reason: FailureReason::Build,
+        }));
     }
 
     Ok(())
 }
 
+/// Reads the next line, closing the stream once it has ended.
+async fn next_line<R>(lines: &mut Option<tokio::io::Lines<BufReader<R>>>) -> Option<Option<String>>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    let line = lines.as_mut()?.next_line().await.ok().flatten();
+    if line.is_none() {
+        *lines = None;
+    }
+    Some(line)
+}
+
 #[derive(Debug, Error)]
 enum AppError {
     #[error("Failed to make a request: {0}")]
@@ -120,8 +633,10 @@ enum AppError {
     Io(#[from] std::io::Error),
     #[error("Deserialize error: {0}")]
     Deserialize(#[from] serde_json::Error),
-    #[error("Failed to run process")]
-    ProcessFailed,
+    #[error("'{}' did not exit successfully", .0.command)]
+    ProcessFailed(BuildFailure),
+    #[error("Failed to upload the artifacts, {0}")]
+    Upload(String),
     #[error("Invalid header value: {0}")]
     Header(#[from] reqwest::header::InvalidHeaderValue),
 }